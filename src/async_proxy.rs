@@ -0,0 +1,409 @@
+// server_proxy 的 tokio 版本, 需要打开 tokio 这个 feature
+// 查询和回复的逻辑都和同步版本共用, 这里只有 IO 部分
+use crate::byte_packet_buffer::{
+    BytePacketBuffer, DnsPacket, QueryType, VectorPacketBuffer, TCP_MAX_SIZE, UDP_MAX_SIZE,
+};
+use crate::dns_name::DnsName;
+use crate::error::{Error, Result};
+use crate::server_proxy::{
+    encode_response, finish_response, local_addr_for, matches_query, outgoing_query, parse_request,
+    prepare_response, udp_response_size, Action, Attempts, Budget, CnameChain, Limits, Prepared,
+    Resolution, Resolver, EDNS_UDP_PAYLOAD_SIZE, PRIMING_LIMITS, TCP_IDLE_TIMEOUT,
+};
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::Semaphore;
+use tokio::time::{self, Instant};
+
+fn timed_out() -> Error {
+    Error::Io(io::Error::new(
+        ErrorKind::TimedOut,
+        "no matching response from upstream",
+    ))
+}
+
+// 超时也当成 io 错误, 这样 Attempts 会在下一轮重试
+async fn with_timeout<T>(timeout: Duration, future: impl Future<Output = Result<T>>) -> Result<T> {
+    time::timeout(timeout, future)
+        .await
+        .unwrap_or_else(|_| Err(timed_out()))
+}
+
+async fn lookup_udp(
+    qname: &DnsName,
+    qtype: QueryType,
+    server: (IpAddr, u16),
+    timeout: Duration,
+    randomize_case: bool,
+) -> Result<DnsPacket> {
+    let server = SocketAddr::from(server);
+    let socket = UdpSocket::bind(local_addr_for(server)).await?;
+
+    let mut packet = outgoing_query(qname, qtype, randomize_case);
+    let mut req_buffer = BytePacketBuffer::new();
+    packet.write(&mut req_buffer)?;
+    socket
+        .send_to(&req_buffer.buf[0..req_buffer.pos], server)
+        .await?;
+
+    // 对不上的包直接丢掉继续等, 但总的等待时间不能超过 timeout
+    let deadline = Instant::now() + timeout;
+    let mut data = vec![0; EDNS_UDP_PAYLOAD_SIZE as usize];
+    loop {
+        let (size, src_addr) = time::timeout_at(deadline, socket.recv_from(&mut data))
+            .await
+            .map_err(|_| timed_out())??;
+        if src_addr != server {
+            eprintln!("Ignoring datagram from {}, expected {}", src_addr, server);
+            continue;
+        }
+
+        let mut res_buffer = VectorPacketBuffer::from_bytes(&data[..size]);
+        match DnsPacket::from_buffer(&mut res_buffer) {
+            Ok(response) if matches_query(&packet, &response, randomize_case) => {
+                return Ok(response)
+            }
+            Ok(response) => eprintln!(
+                "Ignoring mismatched response {} from {}",
+                response.header.id, server
+            ),
+            Err(e) => eprintln!("Ignoring malformed response from {}: {}", server, e),
+        }
+    }
+}
+
+async fn lookup_tcp(
+    qname: &DnsName,
+    qtype: QueryType,
+    server: (IpAddr, u16),
+    randomize_case: bool,
+) -> Result<DnsPacket> {
+    let mut stream = TcpStream::connect(server).await?;
+
+    let mut packet = outgoing_query(qname, qtype, randomize_case);
+    let mut req_buffer = VectorPacketBuffer::new();
+    packet.write(&mut req_buffer)?;
+    write_tcp_message(&mut stream, &req_buffer.buf).await?;
+
+    let data = match read_tcp_message(&mut stream).await? {
+        Some(data) => data,
+        None => {
+            return Err(Error::Io(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "connection closed before response",
+            )))
+        }
+    };
+
+    let mut res_buffer = VectorPacketBuffer::from_bytes(&data);
+    let response = DnsPacket::from_buffer(&mut res_buffer)?;
+    if !matches_query(&packet, &response, randomize_case) {
+        return Err(Error::Malformed {
+            offset: 0,
+            reason: "response does not match query",
+        });
+    }
+
+    Ok(response)
+}
+
+// 先走 UDP, 回复被截断 (TC) 了再用 TCP 重新问一遍
+pub async fn lookup(
+    qname: &DnsName,
+    qtype: QueryType,
+    server: (IpAddr, u16),
+    timeout: Duration,
+) -> Result<DnsPacket> {
+    lookup_with(qname, qtype, server, timeout, false).await
+}
+
+// 和 server_proxy::lookup_with 一样, randomize_case 时使用 0x20
+pub async fn lookup_with(
+    qname: &DnsName,
+    qtype: QueryType,
+    server: (IpAddr, u16),
+    timeout: Duration,
+    randomize_case: bool,
+) -> Result<DnsPacket> {
+    let response = lookup_udp(qname, qtype, server, timeout, randomize_case).await?;
+    if response.header.truncated_message {
+        println!("truncated response from {:?}, retrying over tcp", server);
+        let tcp = lookup_tcp(qname, qtype, server, randomize_case);
+        return with_timeout(timeout, tcp).await;
+    }
+
+    Ok(response)
+}
+
+pub async fn lookup_servers(
+    qname: &DnsName,
+    qtype: QueryType,
+    servers: &[(IpAddr, u16)],
+) -> Result<DnsPacket> {
+    let mut budget = Budget::new(Limits::default());
+    lookup_servers_within(qname, qtype, servers, false, &mut budget).await
+}
+
+async fn lookup_servers_within(
+    qname: &DnsName,
+    qtype: QueryType,
+    servers: &[(IpAddr, u16)],
+    randomize_case: bool,
+    budget: &mut Budget,
+) -> Result<DnsPacket> {
+    let mut attempts = Attempts::new(servers);
+    while let Some((server, timeout)) = attempts.next_server() {
+        budget.spend_query()?;
+        let result = lookup_with(qname, qtype, server, timeout, randomize_case).await;
+        if let Some(response) = attempts.record(qname, server, result) {
+            return Ok(response);
+        }
+    }
+
+    attempts.finish()
+}
+
+// 和 Resolver::recursive_lookup 一样, 共用同一个缓存
+pub async fn recursive_lookup(
+    resolver: &Resolver,
+    qname: &DnsName,
+    qtype: QueryType,
+) -> Result<DnsPacket> {
+    lookup_within(resolver, qname, qtype, &mut Budget::new(resolver.limits())).await
+}
+
+async fn lookup_within(
+    resolver: &Resolver,
+    qname: &DnsName,
+    qtype: QueryType,
+    budget: &mut Budget,
+) -> Result<DnsPacket> {
+    let mut chain = CnameChain::new(qname, qtype);
+    let mut response = resolve(resolver, qname, qtype, budget).await?;
+    while let Some(target) = chain.follow(&response) {
+        response = resolve(resolver, &target, qtype, budget).await?;
+    }
+
+    Ok(chain.finish(response))
+}
+
+async fn resolve(
+    resolver: &Resolver,
+    qname: &DnsName,
+    qtype: QueryType,
+    budget: &mut Budget,
+) -> Result<DnsPacket> {
+    let mut resolution = Resolution::new(resolver, qname, qtype);
+    let mut action = resolution.next_query(resolver);
+    loop {
+        action = match action {
+            Action::Query(ask, ask_type, servers) => {
+                let result = lookup_servers_within(
+                    &ask,
+                    ask_type,
+                    &servers,
+                    resolver.case_randomization(),
+                    budget,
+                )
+                .await;
+                resolution.answered(resolver, result, budget)?
+            }
+            Action::LookupNs(ns_name, ns_qtype) => {
+                budget.enter_ns_lookup()?;
+                // 递归的 async fn 要放到堆上
+                let result = Box::pin(lookup_within(resolver, &ns_name, ns_qtype, budget)).await;
+                budget.leave_ns_lookup();
+                resolution.ns_resolved(resolver, &ns_name, result)?
+            }
+            Action::Done(response) => return Ok(response),
+        };
+    }
+}
+
+// 向 hints 里的根服务器查询 `. NS`, 拿到最新的根服务器列表
+pub async fn prime(resolver: &Resolver) -> Result<()> {
+    let response = lookup_servers_within(
+        &DnsName::root(),
+        QueryType::NS,
+        &resolver.priming_servers(),
+        false,
+        &mut Budget::new(PRIMING_LIMITS),
+    )
+    .await?;
+    resolver.apply_priming(&response)
+}
+
+async fn read_tcp_message(stream: &mut TcpStream) -> Result<Option<Vec<u8>>> {
+    let mut len_buf = [0; 2];
+    match stream.read_exact(&mut len_buf).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let len = u16::from_be_bytes(len_buf) as usize;
+    let mut data = vec![0; len];
+    stream.read_exact(&mut data).await?;
+
+    Ok(Some(data))
+}
+
+async fn write_tcp_message(stream: &mut TcpStream, data: &[u8]) -> Result<()> {
+    if data.len() > TCP_MAX_SIZE {
+        return Err(Error::BufferFull {
+            offset: TCP_MAX_SIZE,
+        });
+    }
+
+    let mut message = Vec::with_capacity(data.len() + 2);
+    message.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    message.extend_from_slice(data);
+    stream.write_all(&message).await?;
+
+    Ok(())
+}
+
+async fn build_response(
+    resolver: &Resolver,
+    request_packet: DnsPacket,
+    client: SocketAddr,
+) -> DnsPacket {
+    match prepare_response(request_packet, client) {
+        Prepared::Done(response_packet) => response_packet,
+        Prepared::Resolve(response_packet, question) => {
+            let result = recursive_lookup(resolver, &question.name, question.qtype).await;
+            finish_response(response_packet, question, result, client)
+        }
+    }
+}
+
+// 处理一个已经收到的 UDP 请求, 回复从同一个 socket 发回去
+pub async fn handle_datagram(
+    socket: &UdpSocket,
+    resolver: &Resolver,
+    data: &[u8],
+    src_addr: SocketAddr,
+) -> Result<()> {
+    let (mut response_packet, max_size) = match parse_request(data, src_addr) {
+        Some(Ok(request_packet)) => {
+            let max_size = udp_response_size(&request_packet);
+            let response_packet = build_response(resolver, request_packet, src_addr).await;
+            (response_packet, max_size)
+        }
+        Some(Err(response_packet)) => (response_packet, UDP_MAX_SIZE),
+        None => return Ok(()),
+    };
+
+    let res_data = encode_response(&mut response_packet, max_size)?;
+    socket.send_to(&res_data, src_addr).await?;
+
+    Ok(())
+}
+
+// 一个连接上可以连续发多个请求, 直到客户端关闭或者空闲超时
+pub async fn handle_tcp_connection(mut stream: TcpStream, resolver: &Resolver) -> Result<()> {
+    let peer_addr = stream.peer_addr()?;
+
+    loop {
+        let data = match time::timeout(TCP_IDLE_TIMEOUT, read_tcp_message(&mut stream)).await {
+            Ok(data) => data?,
+            // 空闲超时, 直接关掉连接
+            Err(_) => None,
+        };
+        let data = match data {
+            Some(data) => data,
+            None => return Ok(()),
+        };
+
+        let mut response_packet = match parse_request(&data, peer_addr) {
+            Some(Ok(request_packet)) => build_response(resolver, request_packet, peer_addr).await,
+            Some(Err(response_packet)) => response_packet,
+            None => continue,
+        };
+
+        let res_data = encode_response(&mut response_packet, TCP_MAX_SIZE)?;
+        write_tcp_message(&mut stream, &res_data).await?;
+    }
+}
+
+// 每个请求一个 task, 同时最多 max_in_flight 个, 超过的直接丢掉, 客户端会自己重试
+pub async fn serve_udp(
+    socket: UdpSocket,
+    resolver: Arc<Resolver>,
+    max_in_flight: usize,
+) -> Result<()> {
+    let socket = Arc::new(socket);
+    let permits = Arc::new(Semaphore::new(max_in_flight));
+
+    loop {
+        let mut data = vec![0; EDNS_UDP_PAYLOAD_SIZE as usize];
+        let (size, src_addr) = match socket.recv_from(&mut data).await {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("An error occurred: {}", e);
+                continue;
+            }
+        };
+        data.truncate(size);
+
+        let permit = match permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                eprintln!(
+                    "Too many queries in flight, dropping query from {}",
+                    src_addr
+                );
+                continue;
+            }
+        };
+
+        let socket = socket.clone();
+        let resolver = resolver.clone();
+        tokio::spawn(async move {
+            let _permit = permit;
+            if let Err(e) = handle_datagram(&socket, &resolver, &data, src_addr).await {
+                eprintln!("An error occurred handling {}: {}", src_addr, e);
+            }
+        });
+    }
+}
+
+// 每个 TCP 连接一个 task, 同时最多 max_connections 个, 超过的直接关掉
+pub async fn serve_tcp(
+    listener: TcpListener,
+    resolver: Arc<Resolver>,
+    max_connections: usize,
+) -> Result<()> {
+    let permits = Arc::new(Semaphore::new(max_connections));
+
+    loop {
+        let (stream, peer_addr) = match listener.accept().await {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("An error occurred on tcp: {}", e);
+                continue;
+            }
+        };
+
+        let permit = match permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                eprintln!("Too many tcp connections, closing {}", peer_addr);
+                continue;
+            }
+        };
+
+        let resolver = resolver.clone();
+        tokio::spawn(async move {
+            let _permit = permit;
+            if let Err(e) = handle_tcp_connection(stream, &resolver).await {
+                eprintln!("An error occurred on tcp: {}", e);
+            }
+        });
+    }
+}
@@ -1,58 +1,30 @@
-use std::net::{Ipv4Addr, Ipv6Addr};
-
-type Error = Box<dyn std::error::Error>;
-type Result<T> = std::result::Result<T, Error>;
-
-pub struct BytePacketBuffer {
-    pub buf: [u8; 512],
-    pub pos: usize,
-}
-
-impl BytePacketBuffer {
-    pub fn new() -> BytePacketBuffer {
-        BytePacketBuffer {
-            buf: [0; 512],
-            pos: 0,
-        }
-    }
-
-    pub fn pos(&self) -> usize {
-        self.pos
-    }
-
-    fn step(&mut self, steps: usize) {
-        self.pos += steps;
-    }
-
-    fn seek(&mut self, pos: usize) {
-        self.pos = pos;
-    }
-
-    fn read(&mut self) -> Result<u8> {
-        if self.pos >= 512 {
-            return Err("End of buffer".into());
-        }
-        let res = self.buf[self.pos];
-        self.pos += 1;
-
-        Ok(res)
-    }
-
-    fn get(&mut self, pos: usize) -> Result<u8> {
-        if pos >= 512 {
-            return Err("End of buffer".into());
-        }
-
-        Ok(self.buf[pos])
-    }
-
-    pub fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
-        if start + len >= 512 {
-            return Err("End of buffer".into());
-        }
-
-        Ok(&self.buf[start..start + len])
-    }
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::dns_name::DnsName;
+use crate::error::{Error, Result};
+
+// UDP 不带 EDNS 时的最大长度
+pub const UDP_MAX_SIZE: usize = 512;
+// TCP 前面的 2 字节长度决定了最大只能是 65535
+pub const TCP_MAX_SIZE: usize = 65535;
+
+// header, question, record 的读写都只依赖这个 trait,
+// 底下是栈上固定 512 字节的数组, 还是可以增长的 Vec 都可以
+pub trait PacketBuffer {
+    fn read(&mut self) -> Result<u8>;
+    fn get(&mut self, pos: usize) -> Result<u8>;
+    fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]>;
+    fn write(&mut self, val: u8) -> Result<()>;
+    fn set(&mut self, pos: usize, val: u8) -> Result<()>;
+    fn pos(&self) -> usize;
+    fn seek(&mut self, pos: usize);
+    fn step(&mut self, steps: usize);
+
+    // 压缩用: 已经写过的名字后缀在 buffer 中的 offset
+    fn find_label(&self, name: &DnsName) -> Option<usize>;
+    fn save_label(&mut self, name: DnsName, pos: usize);
 
     fn read_u16(&mut self) -> Result<u16> {
         let res = ((self.read()? as u16) << 8) | (self.read()? as u16);
@@ -63,22 +35,24 @@ impl BytePacketBuffer {
         let res = ((self.read()? as u32) << 24)
             | ((self.read()? as u32) << 16)
             | ((self.read()? as u32) << 8)
-            | ((self.read()? as u32) << 0);
+            | (self.read()? as u32);
 
         Ok(res)
     }
 
-    fn read_qname(&mut self, outstr: &mut String) -> Result<()> {
+    fn read_qname(&mut self) -> Result<DnsName> {
         let mut pos = self.pos();
         let mut jumped = false;
         let max_jumps = 5;
         let mut jumps_performed = 0;
 
-        let mut delimiter = "";
+        let mut labels = Vec::new();
+        // 名字在报文中的长度, 包括每个 label 前面的长度和最后的 0
+        let mut name_len = 1;
 
         loop {
             if jumps_performed > max_jumps {
-                return Err(format!("Limit of {} jumps exceeded", max_jumps).into());
+                return Err(Error::PointerLoop { offset: pos });
             }
 
             let len = self.get(pos)?;
@@ -98,6 +72,14 @@ impl BytePacketBuffer {
 
                 continue;
             } else {
+                // 01 和 10 开头的是已经废弃的扩展 label 类型
+                if (len & 0xc0) != 0 {
+                    return Err(Error::BadLabel {
+                        offset: pos,
+                        reason: "reserved label type",
+                    });
+                }
+
                 // len 读过了, 所有向前移动一个
                 pos += 1;
 
@@ -105,14 +87,14 @@ impl BytePacketBuffer {
                     break;
                 }
 
-                outstr.push_str(delimiter);
-
-                let str_buffer = self.get_range(pos, len as usize)?;
-                outstr.push_str(&String::from_utf8_lossy(str_buffer).to_lowercase());
+                name_len += len as usize + 1;
+                if name_len > 255 {
+                    return Err(Error::NameTooLong { offset: pos - 1 });
+                }
 
-                // 放在后面是为了最后一个就可以不放了
-                // 比如 google.com, 而不必是 google.com.
-                delimiter = ".";
+                // 保留原来的大小写, 0x20 要逐位比较 question 里的名字
+                // 其他地方比较名字时都不区分大小写
+                labels.push(self.get_range(pos, len as usize)?.to_vec());
                 pos += len as usize;
             }
         }
@@ -123,22 +105,11 @@ impl BytePacketBuffer {
             self.seek(pos);
         }
 
-        Ok(())
-    }
-
-    fn write(&mut self, val: u8) -> Result<()> {
-        if self.pos >= 512 {
-            return Err("End of buffer".into());
-        }
-
-        self.buf[self.pos] = val;
-        self.pos += 1;
-
-        Ok(())
+        Ok(DnsName::from_wire_labels(labels))
     }
 
     fn write_u8(&mut self, val: u8) -> Result<()> {
-        return self.write(val);
+        self.write(val)
     }
 
     fn write_u16(&mut self, val: u16) -> Result<()> {
@@ -153,21 +124,45 @@ impl BytePacketBuffer {
         self.write((val >> 24) as u8)?;
         self.write((val >> 16) as u8)?;
         self.write((val >> 8) as u8)?;
-        self.write((val >> 0) as u8)?;
+        self.write(val as u8)?;
 
         Ok(())
     }
 
-    fn write_qname(&mut self, qname: &str) -> Result<()> {
-        for label in qname.split('.') {
-            let len = label.len();
-            if len > 0x3f {
-                // 因为 label len 的前两个位有可能代表 jump, 所以 len 就只能用后面的 6 位了
-                return Err("Single label exceeds 63 characters of length".into());
+    // 长度在构造 DnsName 的时候已经检查过了
+    fn write_qname(&mut self, qname: &DnsName) -> Result<()> {
+        let count = qname.label_count();
+        for (i, label) in qname.labels().enumerate() {
+            // 这个后缀之前写过, 直接写一个指向它的指针就结束了
+            // 比如已经写过 google.com, 那么 www.google.com 只需要写 www 加一个指针
+            let suffix = qname.suffix(count - i);
+            if let Some(offset) = self.find_label(&suffix) {
+                self.write_u16(0xc000 | offset as u16)?;
+                return Ok(());
+            }
+
+            // 指针只有 14 位, 超过的位置就没法被指向了
+            let pos = self.pos();
+            if pos <= 0x3fff {
+                self.save_label(suffix, pos);
+            }
+
+            self.write_u8(label.len() as u8)?;
+            for b in label {
+                self.write_u8(*b)?;
             }
+        }
+
+        self.write_u8(0)?;
+
+        Ok(())
+    }
 
-            self.write_u8(len as u8)?;
-            for b in label.as_bytes() {
+    // 有些地方不允许压缩, 比如 SRV 的 target
+    fn write_qname_uncompressed(&mut self, qname: &DnsName) -> Result<()> {
+        for label in qname.labels() {
+            self.write_u8(label.len() as u8)?;
+            for b in label {
                 self.write_u8(*b)?;
             }
         }
@@ -177,13 +172,221 @@ impl BytePacketBuffer {
         Ok(())
     }
 
-    fn set(&mut self, pos: usize, val: u8) {
+    fn set_u16(&mut self, pos: usize, val: u16) -> Result<()> {
+        self.set(pos, (val >> 8 & 0xff) as u8)?;
+        self.set(pos + 1, (val & 0xff) as u8)?;
+
+        Ok(())
+    }
+}
+
+// DnsName 的 == 和 hash 不区分大小写, 压缩表要用原样的 label
+// 不然只差大小写的名字会指向前面那个, 读回来大小写就变了
+fn compression_key(name: &DnsName) -> Vec<Vec<u8>> {
+    name.labels().map(<[u8]>::to_vec).collect()
+}
+
+// 普通 UDP 用的, 栈上固定 512 字节
+pub struct BytePacketBuffer {
+    pub buf: [u8; UDP_MAX_SIZE],
+    pub pos: usize,
+    // 已经写过的名字后缀 -> 在 buf 中的 offset, 用于压缩
+    names: HashMap<Vec<Vec<u8>>, usize>,
+}
+
+impl Default for BytePacketBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BytePacketBuffer {
+    pub fn new() -> BytePacketBuffer {
+        BytePacketBuffer {
+            buf: [0; UDP_MAX_SIZE],
+            pos: 0,
+            names: HashMap::new(),
+        }
+    }
+}
+
+impl PacketBuffer for BytePacketBuffer {
+    fn read(&mut self) -> Result<u8> {
+        if self.pos >= UDP_MAX_SIZE {
+            return Err(Error::Truncated { offset: self.pos });
+        }
+        let res = self.buf[self.pos];
+        self.pos += 1;
+
+        Ok(res)
+    }
+
+    fn get(&mut self, pos: usize) -> Result<u8> {
+        if pos >= UDP_MAX_SIZE {
+            return Err(Error::Truncated { offset: pos });
+        }
+
+        Ok(self.buf[pos])
+    }
+
+    fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
+        if start + len > UDP_MAX_SIZE {
+            return Err(Error::Truncated { offset: start });
+        }
+
+        Ok(&self.buf[start..start + len])
+    }
+
+    fn write(&mut self, val: u8) -> Result<()> {
+        if self.pos >= UDP_MAX_SIZE {
+            return Err(Error::BufferFull { offset: self.pos });
+        }
+
+        self.buf[self.pos] = val;
+        self.pos += 1;
+
+        Ok(())
+    }
+
+    fn set(&mut self, pos: usize, val: u8) -> Result<()> {
+        if pos >= UDP_MAX_SIZE {
+            return Err(Error::BufferFull { offset: pos });
+        }
+
         self.buf[pos] = val;
+
+        Ok(())
+    }
+
+    fn pos(&self) -> usize {
+        self.pos
+    }
+
+    fn seek(&mut self, pos: usize) {
+        self.pos = pos;
+    }
+
+    fn step(&mut self, steps: usize) {
+        self.pos += steps;
+    }
+
+    fn find_label(&self, name: &DnsName) -> Option<usize> {
+        self.names.get(&compression_key(name)).copied()
+    }
+
+    fn save_label(&mut self, name: DnsName, pos: usize) {
+        self.names.insert(compression_key(&name), pos);
     }
+}
 
-    fn set_u16(&mut self, pos: usize, val: u16) {
-        self.set(pos, (val >> 8 & 0xff) as u8);
-        self.set(pos + 1, (val >> 0 & 0xff) as u8);
+// EDNS, TCP 和 zone transfer 用的, 写的时候按需增长, 最多到 limit
+pub struct VectorPacketBuffer {
+    pub buf: Vec<u8>,
+    pub pos: usize,
+    limit: usize,
+    names: HashMap<Vec<Vec<u8>>, usize>,
+}
+
+impl Default for VectorPacketBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VectorPacketBuffer {
+    pub fn new() -> VectorPacketBuffer {
+        VectorPacketBuffer::with_limit(TCP_MAX_SIZE)
+    }
+
+    pub fn with_limit(limit: usize) -> VectorPacketBuffer {
+        VectorPacketBuffer {
+            buf: Vec::new(),
+            pos: 0,
+            limit,
+            names: HashMap::new(),
+        }
+    }
+
+    // 用收到的数据构造, 用来解析
+    pub fn from_bytes(data: &[u8]) -> VectorPacketBuffer {
+        let mut buffer = VectorPacketBuffer::with_limit(data.len().max(TCP_MAX_SIZE));
+        buffer.buf.extend_from_slice(data);
+        buffer
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+}
+
+impl PacketBuffer for VectorPacketBuffer {
+    fn read(&mut self) -> Result<u8> {
+        let res = self.get(self.pos)?;
+        self.pos += 1;
+
+        Ok(res)
+    }
+
+    fn get(&mut self, pos: usize) -> Result<u8> {
+        match self.buf.get(pos) {
+            Some(b) => Ok(*b),
+            None => Err(Error::Truncated { offset: pos }),
+        }
+    }
+
+    fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
+        if start + len > self.buf.len() {
+            return Err(Error::Truncated { offset: start });
+        }
+
+        Ok(&self.buf[start..start + len])
+    }
+
+    fn write(&mut self, val: u8) -> Result<()> {
+        if self.pos >= self.limit {
+            return Err(Error::BufferFull { offset: self.pos });
+        }
+
+        if self.pos < self.buf.len() {
+            self.buf[self.pos] = val;
+        } else {
+            // seek 到后面再写的话, 中间补 0
+            self.buf.resize(self.pos, 0);
+            self.buf.push(val);
+        }
+        self.pos += 1;
+
+        Ok(())
+    }
+
+    fn set(&mut self, pos: usize, val: u8) -> Result<()> {
+        match self.buf.get_mut(pos) {
+            Some(b) => {
+                *b = val;
+                Ok(())
+            }
+            None => Err(Error::BufferFull { offset: pos }),
+        }
+    }
+
+    fn pos(&self) -> usize {
+        self.pos
+    }
+
+    fn seek(&mut self, pos: usize) {
+        self.pos = pos;
+    }
+
+    fn step(&mut self, steps: usize) {
+        self.pos += steps;
+    }
+
+    fn find_label(&self, name: &DnsName) -> Option<usize> {
+        self.names.get(&compression_key(name)).copied()
+    }
+
+    fn save_label(&mut self, name: DnsName, pos: usize) {
+        self.names.insert(compression_key(&name), pos);
     }
 }
 
@@ -205,7 +408,7 @@ impl ResultCode {
             3 => ResultCode::NXDOMAIN,
             4 => ResultCode::NOTIMP,
             5 => ResultCode::REFUSED,
-            0 | _ => ResultCode::NOERROR,
+            _ => ResultCode::NOERROR,
         }
     }
 }
@@ -232,6 +435,12 @@ pub struct DnsHeader {
     pub resource_entries: u16,      // 16 bit   Additional Section
 }
 
+impl Default for DnsHeader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DnsHeader {
     pub fn new() -> DnsHeader {
         DnsHeader {
@@ -255,7 +464,7 @@ impl DnsHeader {
         }
     }
 
-    pub fn read(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
+    pub fn read<T: PacketBuffer>(&mut self, buffer: &mut T) -> Result<()> {
         self.id = buffer.read_u16()?;
 
         // let flags = buffer.read_u16()?;
@@ -285,7 +494,7 @@ impl DnsHeader {
         Ok(())
     }
 
-    pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<()> {
+    pub fn write<T: PacketBuffer>(&self, buffer: &mut T) -> Result<()> {
         buffer.write_u16(self.id)?;
 
         // buffer is a big endian
@@ -320,8 +529,13 @@ pub enum QueryType {
     A,     // 1
     NS,    // 2
     CNAME, // 5
+    SOA,   // 6
+    PTR,   // 12
     MX,    // 15
+    TXT,   // 16
     AAAA,  // 28
+    SRV,   // 33
+    OPT,   // 41
 }
 
 impl QueryType {
@@ -331,8 +545,13 @@ impl QueryType {
             QueryType::A => 1,
             QueryType::NS => 2,
             QueryType::CNAME => 5,
+            QueryType::SOA => 6,
+            QueryType::PTR => 12,
             QueryType::MX => 15,
+            QueryType::TXT => 16,
             QueryType::AAAA => 28,
+            QueryType::SRV => 33,
+            QueryType::OPT => 41,
         }
     }
 
@@ -341,42 +560,96 @@ impl QueryType {
             1 => QueryType::A,
             2 => QueryType::NS,
             5 => QueryType::CNAME,
+            6 => QueryType::SOA,
+            12 => QueryType::PTR,
             15 => QueryType::MX,
+            16 => QueryType::TXT,
             28 => QueryType::AAAA,
+            33 => QueryType::SRV,
+            41 => QueryType::OPT,
             _ => QueryType::UNKNOWN(num),
         }
     }
 }
 
+#[derive(PartialEq, Eq, Debug, Clone, Hash, Copy, PartialOrd, Ord)]
+pub enum QueryClass {
+    UNKNOWN(u16),
+    IN,   // 1
+    CH,   // 3
+    HS,   // 4
+    NONE, // 254
+    ANY,  // 255
+}
+
+impl QueryClass {
+    pub fn to_num(&self) -> u16 {
+        match *self {
+            QueryClass::UNKNOWN(x) => x,
+            QueryClass::IN => 1,
+            QueryClass::CH => 3,
+            QueryClass::HS => 4,
+            QueryClass::NONE => 254,
+            QueryClass::ANY => 255,
+        }
+    }
+
+    pub fn from_num(num: u16) -> QueryClass {
+        match num {
+            1 => QueryClass::IN,
+            3 => QueryClass::CH,
+            4 => QueryClass::HS,
+            254 => QueryClass::NONE,
+            255 => QueryClass::ANY,
+            _ => QueryClass::UNKNOWN(num),
+        }
+    }
+}
+
+impl fmt::Display for QueryClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            QueryClass::UNKNOWN(x) => write!(f, "CLASS{}", x),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DnsQuestion {
-    pub name: String,
-    pub qtype: QueryType, // 16 bit
+    pub name: DnsName,
+    pub qtype: QueryType,  // 16 bit
+    pub class: QueryClass, // 16 bit
 }
 
 // name
 // type: 16
 // class: 16
 impl DnsQuestion {
-    pub fn new(name: String, qtype: QueryType) -> DnsQuestion {
-        DnsQuestion { name, qtype }
+    // 绝大多数查询都是 IN, 别的 class 直接改 class 字段
+    pub fn new(name: DnsName, qtype: QueryType) -> DnsQuestion {
+        DnsQuestion {
+            name,
+            qtype,
+            class: QueryClass::IN,
+        }
     }
 
-    pub fn read(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
-        buffer.read_qname(&mut self.name)?;
+    pub fn read<T: PacketBuffer>(&mut self, buffer: &mut T) -> Result<()> {
+        self.name = buffer.read_qname()?;
         self.qtype = QueryType::from_num(buffer.read_u16()?);
-        let _ = buffer.read_u16()?;
+        self.class = QueryClass::from_num(buffer.read_u16()?);
 
         Ok(())
     }
 
-    pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<()> {
+    pub fn write<T: PacketBuffer>(&self, buffer: &mut T) -> Result<()> {
         buffer.write_qname(&self.name)?;
 
         let typenum = self.qtype.to_num();
         // buffer.write_u16(self.qtype.to_num())?;
         buffer.write_u16(typenum)?;
-        buffer.write_u16(1)?; // 1 is Class 0x0001
+        buffer.write_u16(self.class.to_num())?;
 
         Ok(())
     }
@@ -386,35 +659,74 @@ impl DnsQuestion {
 #[allow(dead_code)]
 pub enum DnsRecord {
     UNKNOWN {
-        domain: String,
+        domain: DnsName,
         qtype: u16,
-        data_len: u16,
+        data: Vec<u8>, // 不认识的 type, 原样保留 rdata, RFC 3597
+        class: QueryClass,
         ttl: u32,
     },
     A {
-        domain: String,
+        domain: DnsName,
         addr: Ipv4Addr,
+        class: QueryClass,
         ttl: u32,
     },
     NS {
-        domain: String,
-        host: String,
+        domain: DnsName,
+        host: DnsName,
+        class: QueryClass,
         ttl: u32,
     },
     CNAME {
-        domain: String,
-        host: String,
+        domain: DnsName,
+        host: DnsName,
+        class: QueryClass,
+        ttl: u32,
+    },
+    SOA {
+        domain: DnsName,
+        mname: DnsName,
+        rname: DnsName,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+        class: QueryClass,
+        ttl: u32,
+    },
+    PTR {
+        domain: DnsName,
+        host: DnsName,
+        class: QueryClass,
         ttl: u32,
     },
     MX {
-        domain: String,
+        domain: DnsName,
         priority: u16,
-        host: String,
+        host: DnsName,
+        class: QueryClass,
+        ttl: u32,
+    },
+    TXT {
+        domain: DnsName,
+        data: Vec<Vec<u8>>, // 多个 character-string, 每个最长 255, 不一定是 UTF-8
+        class: QueryClass,
         ttl: u32,
     },
     AAAA {
-        domain: String,
+        domain: DnsName,
         addr: Ipv6Addr,
+        class: QueryClass,
+        ttl: u32,
+    },
+    SRV {
+        domain: DnsName,
+        priority: u16,
+        weight: u16,
+        port: u16,
+        host: DnsName,
+        class: QueryClass,
         ttl: u32,
     },
 }
@@ -426,27 +738,36 @@ pub enum DnsRecord {
 // data length: 16
 // address:
 impl DnsRecord {
-    pub fn read(buffer: &mut BytePacketBuffer) -> Result<DnsRecord> {
-        let mut domain = String::new();
-        buffer.read_qname(&mut domain)?;
+    pub fn read<T: PacketBuffer>(buffer: &mut T) -> Result<DnsRecord> {
+        let start = buffer.pos();
+        let domain = buffer.read_qname()?;
 
         let qtype_num = buffer.read_u16()?;
         let qtype = QueryType::from_num(qtype_num);
-        let _ = buffer.read_u16()?;
+        let class = QueryClass::from_num(buffer.read_u16()?);
         let ttl = buffer.read_u32()?;
-        let data_len = buffer.read_u16()?;
+        let data_len = buffer.read_u16()? as usize;
+
+        // rdata 要整个在报文里, 解析完也要刚好用完 data_len, 不然后面的记录都会读错
+        let rdata_start = buffer.pos();
+        buffer.get_range(rdata_start, data_len)?;
 
-        match qtype {
+        let record = match qtype {
             QueryType::A => {
                 let raw_addr = buffer.read_u32()?;
                 let addr = Ipv4Addr::new(
                     ((raw_addr >> 24) & 0xff) as u8,
                     ((raw_addr >> 16) & 0xff) as u8,
                     ((raw_addr >> 8) & 0xff) as u8,
-                    ((raw_addr >> 0) & 0xff) as u8,
+                    (raw_addr & 0xff) as u8,
                 );
 
-                Ok(DnsRecord::A { domain, addr, ttl })
+                Ok(DnsRecord::A {
+                    domain,
+                    addr,
+                    class,
+                    ttl,
+                })
             }
             QueryType::AAAA => {
                 let raw_addr1 = buffer.read_u32()?;
@@ -456,60 +777,171 @@ impl DnsRecord {
 
                 let addr = Ipv6Addr::new(
                     ((raw_addr1 >> 16) & 0xFFFF) as u16,
-                    ((raw_addr1 >> 0) & 0xFFFF) as u16,
+                    (raw_addr1 & 0xFFFF) as u16,
                     ((raw_addr2 >> 16) & 0xFFFF) as u16,
-                    ((raw_addr2 >> 0) & 0xFFFF) as u16,
+                    (raw_addr2 & 0xFFFF) as u16,
                     ((raw_addr3 >> 16) & 0xFFFF) as u16,
-                    ((raw_addr3 >> 0) & 0xFFFF) as u16,
+                    (raw_addr3 & 0xFFFF) as u16,
                     ((raw_addr4 >> 16) & 0xFFFF) as u16,
-                    ((raw_addr4 >> 0) & 0xFFFF) as u16,
+                    (raw_addr4 & 0xFFFF) as u16,
                 );
 
-                Ok(DnsRecord::AAAA { domain, addr, ttl })
+                Ok(DnsRecord::AAAA {
+                    domain,
+                    addr,
+                    class,
+                    ttl,
+                })
             }
-            QueryType::NS | QueryType::CNAME => {
-                let mut host = String::new();
-                buffer.read_qname(&mut host)?;
+            QueryType::NS => {
+                let host = buffer.read_qname()?;
 
-                Ok(DnsRecord::NS { domain, host, ttl })
+                Ok(DnsRecord::NS {
+                    domain,
+                    host,
+                    class,
+                    ttl,
+                })
+            }
+            QueryType::CNAME => {
+                let host = buffer.read_qname()?;
+
+                Ok(DnsRecord::CNAME {
+                    domain,
+                    host,
+                    class,
+                    ttl,
+                })
+            }
+            QueryType::SOA => {
+                let mname = buffer.read_qname()?;
+                let rname = buffer.read_qname()?;
+
+                let serial = buffer.read_u32()?;
+                let refresh = buffer.read_u32()?;
+                let retry = buffer.read_u32()?;
+                let expire = buffer.read_u32()?;
+                let minimum = buffer.read_u32()?;
+
+                Ok(DnsRecord::SOA {
+                    domain,
+                    mname,
+                    rname,
+                    serial,
+                    refresh,
+                    retry,
+                    expire,
+                    minimum,
+                    class,
+                    ttl,
+                })
+            }
+            QueryType::PTR => {
+                let host = buffer.read_qname()?;
+
+                Ok(DnsRecord::PTR {
+                    domain,
+                    host,
+                    class,
+                    ttl,
+                })
             }
             QueryType::MX => {
                 let priority = buffer.read_u16()?;
-                let mut host = String::new();
-                buffer.read_qname(&mut host)?;
+                let host = buffer.read_qname()?;
 
                 Ok(DnsRecord::MX {
                     domain,
                     priority,
                     host,
+                    class,
                     ttl,
                 })
             }
+            QueryType::TXT => {
+                // 每个 character-string 前面 1 字节长度, 一直到 data_len 用完
+                let mut data = Vec::new();
+                let end = buffer.pos() + data_len;
+                while buffer.pos() < end {
+                    let len = buffer.read()? as usize;
+                    if buffer.pos() + len > end {
+                        return Err(Error::Malformed {
+                            offset: buffer.pos() - 1,
+                            reason: "TXT string exceeds record length",
+                        });
+                    }
+
+                    let str_buffer = buffer.get_range(buffer.pos(), len)?;
+                    data.push(str_buffer.to_vec());
+                    buffer.step(len);
+                }
 
+                Ok(DnsRecord::TXT {
+                    domain,
+                    data,
+                    class,
+                    ttl,
+                })
+            }
+            QueryType::SRV => {
+                let priority = buffer.read_u16()?;
+                let weight = buffer.read_u16()?;
+                let port = buffer.read_u16()?;
+                let host = buffer.read_qname()?;
+
+                Ok(DnsRecord::SRV {
+                    domain,
+                    priority,
+                    weight,
+                    port,
+                    host,
+                    class,
+                    ttl,
+                })
+            }
+
+            // OPT 只应该出现在 ADDITIONAL SECTION, 由 DnsPacket 单独处理
+            QueryType::OPT => Err(Error::UnsupportedType {
+                offset: start,
+                qtype: qtype_num,
+            }),
             QueryType::UNKNOWN(_) => {
-                buffer.step(data_len as usize);
+                let data = buffer.get_range(buffer.pos(), data_len)?.to_vec();
+                buffer.step(data_len);
+
                 Ok(DnsRecord::UNKNOWN {
                     domain,
                     qtype: qtype_num,
-                    data_len,
+                    data,
+                    class,
                     ttl,
                 })
             }
+        }?;
+
+        if buffer.pos() != rdata_start + data_len {
+            return Err(Error::Malformed {
+                offset: rdata_start,
+                reason: "rdata length mismatch",
+            });
         }
+
+        Ok(record)
     }
 
-    pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<usize> {
+    pub fn write<T: PacketBuffer>(&self, buffer: &mut T) -> Result<usize> {
         let start_pos = buffer.pos();
 
         match *self {
             DnsRecord::A {
                 ref domain,
                 ref addr,
+                class,
                 ttl,
             } => {
-                buffer.write_qname(domain)?; // TODO: 压缩
+                buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::A.to_num())?;
-                buffer.write_u16(0x0001)?; // class
+                buffer.write_u16(class.to_num())?;
                 buffer.write_u32(ttl)?;
                 buffer.write_u16(0x0004)?; // data length. octets.len()?
 
@@ -522,11 +954,12 @@ impl DnsRecord {
             DnsRecord::NS {
                 ref domain,
                 ref host,
+                class,
                 ttl,
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::NS.to_num())?;
-                buffer.write_u16(0x0001)?;
+                buffer.write_u16(class.to_num())?;
                 buffer.write_u32(ttl)?;
 
                 let pos = buffer.pos();
@@ -534,16 +967,17 @@ impl DnsRecord {
 
                 buffer.write_qname(host)?;
                 let size = buffer.pos() - (pos + 2);
-                buffer.set_u16(pos, size as u16);
+                buffer.set_u16(pos, size as u16)?;
             }
             DnsRecord::CNAME {
                 ref domain,
                 ref host,
+                class,
                 ttl,
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::CNAME.to_num())?;
-                buffer.write_u16(0x0001)?;
+                buffer.write_u16(class.to_num())?;
                 buffer.write_u32(ttl)?;
 
                 let pos = buffer.pos();
@@ -552,17 +986,49 @@ impl DnsRecord {
                 buffer.write_qname(host)?;
 
                 let size = buffer.pos() - (pos + 2);
-                buffer.set_u16(pos, size as u16);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::SOA {
+                ref domain,
+                ref mname,
+                ref rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::SOA.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(mname)?;
+                buffer.write_qname(rname)?;
+                buffer.write_u32(serial)?;
+                buffer.write_u32(refresh)?;
+                buffer.write_u32(retry)?;
+                buffer.write_u32(expire)?;
+                buffer.write_u32(minimum)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
             }
             DnsRecord::MX {
                 ref domain,
                 priority,
                 ref host,
+                class,
                 ttl,
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::MX.to_num())?;
-                buffer.write_u16(0x0001)?;
+                buffer.write_u16(class.to_num())?;
                 buffer.write_u32(ttl)?;
 
                 let pos = buffer.pos();
@@ -572,16 +1038,93 @@ impl DnsRecord {
                 buffer.write_qname(host)?;
 
                 let size = buffer.pos() - (pos + 2);
-                buffer.set_u16(pos, size as u16);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::PTR {
+                ref domain,
+                ref host,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::PTR.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::TXT {
+                ref domain,
+                ref data,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::TXT.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                for bytes in data {
+                    if bytes.len() > 0xff {
+                        return Err(Error::Malformed {
+                            offset: buffer.pos(),
+                            reason: "single TXT string exceeds 255 bytes of length",
+                        });
+                    }
+
+                    buffer.write_u8(bytes.len() as u8)?;
+                    for b in bytes {
+                        buffer.write_u8(*b)?;
+                    }
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::SRV {
+                ref domain,
+                priority,
+                weight,
+                port,
+                ref host,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::SRV.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u16(priority)?;
+                buffer.write_u16(weight)?;
+                buffer.write_u16(port)?;
+                // RFC 2782: SRV 的 target 不能压缩
+                buffer.write_qname_uncompressed(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
             }
             DnsRecord::AAAA {
                 ref domain,
                 addr,
+                class,
                 ttl,
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::AAAA.to_num())?;
-                buffer.write_u16(0x0001)?; // class
+                buffer.write_u16(class.to_num())?;
                 buffer.write_u32(ttl)?;
                 buffer.write_u16(0x0010)?;
 
@@ -590,13 +1133,410 @@ impl DnsRecord {
                 }
             }
 
-            DnsRecord::UNKNOWN { .. } => {
-                println!("Skipping record: {:?}", self);
+            DnsRecord::UNKNOWN {
+                ref domain,
+                qtype,
+                ref data,
+                class,
+                ttl,
+            } => {
+                // 不知道 rdata 里面是什么, 所以原样写回去, 也不能压缩
+                buffer.write_qname(domain)?;
+                buffer.write_u16(qtype)?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(data.len() as u16)?;
+
+                for b in data {
+                    buffer.write_u8(*b)?;
+                }
             }
         }
 
         Ok(buffer.pos() - start_pos)
     }
+
+    pub fn domain(&self) -> &DnsName {
+        match self {
+            DnsRecord::UNKNOWN { domain, .. }
+            | DnsRecord::A { domain, .. }
+            | DnsRecord::NS { domain, .. }
+            | DnsRecord::CNAME { domain, .. }
+            | DnsRecord::SOA { domain, .. }
+            | DnsRecord::PTR { domain, .. }
+            | DnsRecord::MX { domain, .. }
+            | DnsRecord::TXT { domain, .. }
+            | DnsRecord::AAAA { domain, .. }
+            | DnsRecord::SRV { domain, .. } => domain,
+        }
+    }
+
+    pub fn query_type(&self) -> QueryType {
+        match *self {
+            DnsRecord::UNKNOWN { qtype, .. } => QueryType::from_num(qtype),
+            DnsRecord::A { .. } => QueryType::A,
+            DnsRecord::NS { .. } => QueryType::NS,
+            DnsRecord::CNAME { .. } => QueryType::CNAME,
+            DnsRecord::SOA { .. } => QueryType::SOA,
+            DnsRecord::PTR { .. } => QueryType::PTR,
+            DnsRecord::MX { .. } => QueryType::MX,
+            DnsRecord::TXT { .. } => QueryType::TXT,
+            DnsRecord::AAAA { .. } => QueryType::AAAA,
+            DnsRecord::SRV { .. } => QueryType::SRV,
+        }
+    }
+
+    pub fn class(&self) -> QueryClass {
+        match *self {
+            DnsRecord::UNKNOWN { class, .. }
+            | DnsRecord::A { class, .. }
+            | DnsRecord::NS { class, .. }
+            | DnsRecord::CNAME { class, .. }
+            | DnsRecord::SOA { class, .. }
+            | DnsRecord::PTR { class, .. }
+            | DnsRecord::MX { class, .. }
+            | DnsRecord::TXT { class, .. }
+            | DnsRecord::AAAA { class, .. }
+            | DnsRecord::SRV { class, .. } => class,
+        }
+    }
+
+    pub fn ttl(&self) -> u32 {
+        match *self {
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::PTR { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::TXT { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::SRV { ttl, .. } => ttl,
+        }
+    }
+
+    pub fn set_ttl(&mut self, new_ttl: u32) {
+        match self {
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::PTR { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::TXT { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::SRV { ttl, .. } => *ttl = new_ttl,
+        }
+    }
+}
+
+// zone 文件里的名字都以 . 结尾, 根就是一个 .
+fn fqdn(name: &DnsName) -> String {
+    format!("{}.", name)
+}
+
+// TXT 的 character-string 加上引号, 引号和反斜杠要转义, 不可见的字符用 \DDD
+fn quote_character_string(string: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for &b in string {
+        match b {
+            b'"' | b'\\' => {
+                out.push('\\');
+                out.push(b as char);
+            }
+            0x20..=0x7e => out.push(b as char),
+            _ => out.push_str(&format!("\\{:03}", b)),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// RFC 3597 的通用格式: \# 长度 十六进制
+pub fn to_generic_rdata(data: &[u8]) -> String {
+    let hex: String = data.iter().map(|b| format!("{:02x}", b)).collect();
+    if hex.is_empty() {
+        "\\# 0".to_string()
+    } else {
+        format!("\\# {} {}", data.len(), hex)
+    }
+}
+
+// 十六进制可以被空格分成好几段, 长度必须和实际的字节数对上
+pub fn parse_generic_rdata(text: &str) -> Result<Vec<u8>> {
+    let mut parts = text.split_whitespace();
+    if parts.next() != Some("\\#") {
+        return Err(Error::Syntax(
+            "generic rdata must start with \\#".to_string(),
+        ));
+    }
+
+    let len: usize = match parts.next().map(|len| len.parse()) {
+        Some(Ok(len)) => len,
+        _ => {
+            return Err(Error::Syntax(
+                "generic rdata is missing its length".to_string(),
+            ))
+        }
+    };
+
+    let hex: String = parts.collect();
+    if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+        return Err(Error::Syntax(
+            "generic rdata must be an even number of hex digits".to_string(),
+        ));
+    }
+
+    let mut data = Vec::with_capacity(hex.len() / 2);
+    for i in (0..hex.len()).step_by(2) {
+        match u8::from_str_radix(&hex[i..i + 2], 16) {
+            Ok(b) => data.push(b),
+            Err(_) => {
+                return Err(Error::Syntax(format!(
+                    "invalid hex digits {:?} in generic rdata",
+                    &hex[i..i + 2]
+                )))
+            }
+        }
+    }
+
+    if data.len() != len {
+        return Err(Error::Syntax(format!(
+            "generic rdata length {} does not match {} bytes",
+            len,
+            data.len()
+        )));
+    }
+
+    Ok(data)
+}
+
+// 按 zone 文件的格式输出, 不认识的 type 用 RFC 3597 的 TYPEnn \# 格式
+impl fmt::Display for DnsRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} ",
+            fqdn(self.domain()),
+            self.ttl(),
+            self.class()
+        )?;
+
+        match self {
+            DnsRecord::UNKNOWN { qtype, data, .. } => {
+                write!(f, "TYPE{} {}", qtype, to_generic_rdata(data))
+            }
+            DnsRecord::A { addr, .. } => write!(f, "A {}", addr),
+            DnsRecord::NS { host, .. } => write!(f, "NS {}", fqdn(host)),
+            DnsRecord::CNAME { host, .. } => write!(f, "CNAME {}", fqdn(host)),
+            DnsRecord::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ..
+            } => write!(
+                f,
+                "SOA {} {} {} {} {} {} {}",
+                fqdn(mname),
+                fqdn(rname),
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum
+            ),
+            DnsRecord::PTR { host, .. } => write!(f, "PTR {}", fqdn(host)),
+            DnsRecord::MX { priority, host, .. } => write!(f, "MX {} {}", priority, fqdn(host)),
+            DnsRecord::TXT { data, .. } => {
+                let strings: Vec<String> = data.iter().map(|s| quote_character_string(s)).collect();
+                write!(f, "TXT {}", strings.join(" "))
+            }
+            DnsRecord::AAAA { addr, .. } => write!(f, "AAAA {}", addr),
+            DnsRecord::SRV {
+                priority,
+                weight,
+                port,
+                host,
+                ..
+            } => write!(f, "SRV {} {} {} {}", priority, weight, port, fqdn(host)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EdnsOption {
+    NSID(Vec<u8>), // 3
+    ClientSubnet {
+        family: u16,
+        source_prefix: u8,
+        scope_prefix: u8,
+        address: Vec<u8>,
+    }, // 8
+    Cookie {
+        client: Vec<u8>,
+        server: Vec<u8>,
+    }, // 10
+    Padding(u16),  // 12
+    UNKNOWN {
+        code: u16,
+        data: Vec<u8>,
+    },
+}
+
+impl EdnsOption {
+    pub fn code(&self) -> u16 {
+        match *self {
+            EdnsOption::NSID(_) => 3,
+            EdnsOption::ClientSubnet { .. } => 8,
+            EdnsOption::Cookie { .. } => 10,
+            EdnsOption::Padding(_) => 12,
+            EdnsOption::UNKNOWN { code, .. } => code,
+        }
+    }
+
+    fn from_data(code: u16, data: &[u8]) -> EdnsOption {
+        match code {
+            3 => EdnsOption::NSID(data.to_vec()),
+            8 if data.len() >= 4 => EdnsOption::ClientSubnet {
+                family: (data[0] as u16) << 8 | data[1] as u16,
+                source_prefix: data[2],
+                scope_prefix: data[3],
+                address: data[4..].to_vec(),
+            },
+            // client cookie 固定 8 字节, server cookie 是 0 或者 8 到 32 字节
+            10 if data.len() >= 8 => EdnsOption::Cookie {
+                client: data[..8].to_vec(),
+                server: data[8..].to_vec(),
+            },
+            12 => EdnsOption::Padding(data.len() as u16),
+            _ => EdnsOption::UNKNOWN {
+                code,
+                data: data.to_vec(),
+            },
+        }
+    }
+
+    fn data(&self) -> Vec<u8> {
+        match self {
+            EdnsOption::NSID(data) => data.clone(),
+            EdnsOption::ClientSubnet {
+                family,
+                source_prefix,
+                scope_prefix,
+                address,
+            } => {
+                let mut data = vec![
+                    (family >> 8) as u8,
+                    *family as u8,
+                    *source_prefix,
+                    *scope_prefix,
+                ];
+                data.extend_from_slice(address);
+                data
+            }
+            EdnsOption::Cookie { client, server } => {
+                let mut data = client.clone();
+                data.extend_from_slice(server);
+                data
+            }
+            EdnsOption::Padding(len) => vec![0; *len as usize],
+            EdnsOption::UNKNOWN { data, .. } => data.clone(),
+        }
+    }
+}
+
+// EDNS(0) 的 OPT 伪记录, RFC 6891
+// name: 0 (root)
+// type: 41
+// class: 16    udp payload size
+// ttl: 32      extended rcode: 8, version: 8, DO: 1, Z: 15
+// data length: 16
+// options: { code: 16, length: 16, data }*
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edns {
+    pub udp_payload_size: u16,
+    pub extended_rcode: u8, // 12 位 rcode 的高 8 位, 低 4 位在 header 里
+    pub version: u8,
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+impl Edns {
+    pub fn new(udp_payload_size: u16) -> Edns {
+        Edns {
+            udp_payload_size,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: Vec::new(),
+        }
+    }
+
+    // name 和 type 已经被 DnsPacket::from_buffer 读过了
+    fn read<T: PacketBuffer>(buffer: &mut T) -> Result<Edns> {
+        let udp_payload_size = buffer.read_u16()?;
+        let flags = buffer.read_u32()?;
+        let data_len = buffer.read_u16()? as usize;
+
+        let mut options = Vec::new();
+        let end = buffer.pos() + data_len;
+        while buffer.pos() < end {
+            let code = buffer.read_u16()?;
+            let len = buffer.read_u16()? as usize;
+            if buffer.pos() + len > end {
+                return Err(Error::Malformed {
+                    offset: buffer.pos() - 4,
+                    reason: "EDNS option exceeds OPT record length",
+                });
+            }
+
+            let data = buffer.get_range(buffer.pos(), len)?;
+            options.push(EdnsOption::from_data(code, data));
+            buffer.step(len);
+        }
+
+        Ok(Edns {
+            udp_payload_size,
+            extended_rcode: (flags >> 24) as u8,
+            version: (flags >> 16) as u8,
+            dnssec_ok: (flags & (1 << 15)) > 0,
+            options,
+        })
+    }
+
+    fn write<T: PacketBuffer>(&self, buffer: &mut T) -> Result<()> {
+        buffer.write_qname(&DnsName::root())?;
+        buffer.write_u16(QueryType::OPT.to_num())?;
+        buffer.write_u16(self.udp_payload_size)?;
+        buffer.write_u32(
+            (self.extended_rcode as u32) << 24
+                | (self.version as u32) << 16
+                | (self.dnssec_ok as u32) << 15,
+        )?;
+
+        let pos = buffer.pos();
+        buffer.write_u16(0)?;
+
+        for option in &self.options {
+            let data = option.data();
+            buffer.write_u16(option.code())?;
+            buffer.write_u16(data.len() as u16)?;
+            for b in data {
+                buffer.write_u8(b)?;
+            }
+        }
+
+        let size = buffer.pos() - (pos + 2);
+        buffer.set_u16(pos, size as u16)?;
+
+        Ok(())
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -606,6 +1546,13 @@ pub struct DnsPacket {
     pub answers: Vec<DnsRecord>,
     pub authorities: Vec<DnsRecord>,
     pub resources: Vec<DnsRecord>,
+    pub edns: Option<Edns>, // ADDITIONAL SECTION 中的 OPT, 不放在 resources 里
+}
+
+impl Default for DnsPacket {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl DnsPacket {
@@ -616,15 +1563,16 @@ impl DnsPacket {
             answers: Vec::new(),
             authorities: Vec::new(),
             resources: Vec::new(),
+            edns: None,
         }
     }
 
-    pub fn from_buffer(buffer: &mut BytePacketBuffer) -> Result<DnsPacket> {
+    pub fn from_buffer<T: PacketBuffer>(buffer: &mut T) -> Result<DnsPacket> {
         let mut result = DnsPacket::new();
         result.header.read(buffer)?;
 
         for _ in 0..result.header.questions {
-            let mut question = DnsQuestion::new("".to_string(), QueryType::UNKNOWN(0));
+            let mut question = DnsQuestion::new(DnsName::root(), QueryType::UNKNOWN(0));
             question.read(buffer)?;
             result.questioins.push(question);
         }
@@ -640,6 +1588,21 @@ impl DnsPacket {
         }
 
         for _ in 0..result.header.resource_entries {
+            // 先看一眼 type, 是 OPT 的话单独解析
+            let start = buffer.pos();
+            buffer.read_qname()?;
+            if QueryType::from_num(buffer.read_u16()?) == QueryType::OPT {
+                if result.edns.is_some() {
+                    return Err(Error::Malformed {
+                        offset: start,
+                        reason: "more than one OPT record",
+                    });
+                }
+                result.edns = Some(Edns::read(buffer)?);
+                continue;
+            }
+
+            buffer.seek(start);
             let rec = DnsRecord::read(buffer)?;
             result.resources.push(rec);
         }
@@ -647,11 +1610,11 @@ impl DnsPacket {
         Ok(result)
     }
 
-    pub fn write(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
+    pub fn write<T: PacketBuffer>(&mut self, buffer: &mut T) -> Result<()> {
         self.header.questions = self.questioins.len() as u16;
         self.header.answers = self.answers.len() as u16;
         self.header.authoritative_entries = self.authorities.len() as u16;
-        self.header.resource_entries = self.resources.len() as u16;
+        self.header.resource_entries = (self.resources.len() + self.edns.is_some() as usize) as u16;
         self.header.write(buffer)?;
 
         for question in &self.questioins {
@@ -670,9 +1633,20 @@ impl DnsPacket {
             rec.write(buffer)?;
         }
 
+        if let Some(edns) = &self.edns {
+            edns.write(buffer)?;
+        }
+
         Ok(())
     }
 
+    // NXDOMAIN 和 NODATA 的 AUTHORITY SECTION 里会带上 zone 的 SOA
+    pub fn get_soa(&self) -> Option<&DnsRecord> {
+        self.authorities
+            .iter()
+            .find(|record| matches!(record, DnsRecord::SOA { .. }))
+    }
+
     pub fn get_random_a(&self) -> Option<Ipv4Addr> {
         self.answers
             .iter()
@@ -683,6 +1657,18 @@ impl DnsPacket {
             .next()
     }
 
+    // answers 里所有的 A 和 AAAA 地址
+    pub fn get_addrs(&self) -> Vec<IpAddr> {
+        self.answers
+            .iter()
+            .filter_map(|record| match record {
+                DnsRecord::A { addr, .. } => Some(IpAddr::from(*addr)),
+                DnsRecord::AAAA { addr, .. } => Some(IpAddr::from(*addr)),
+                _ => None,
+            })
+            .collect()
+    }
+
     // ;; AUTHORITY SECTION:
     // com.			172800	IN	NS	e.gtld-servers.net.
     // com.			172800	IN	NS	b.gtld-servers.net.
@@ -695,44 +1681,85 @@ impl DnsPacket {
     // google.com.		172800	IN	NS	ns1.google.com.
     // google.com.		172800	IN	NS	ns3.google.com.
     // google.com.		172800	IN	NS	ns4.google.com.
-    //                      这里之所以 qname 需要加 ‘a 是因为 qname.ends_with 需要和 domain 比较
-    fn get_ns<'a>(&'a self, qname: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
+    //                      这里之所以 qname 需要加 ‘a 是因为 qname 需要和 domain 比较
+    fn get_ns<'a>(
+        &'a self,
+        qname: &'a DnsName,
+    ) -> impl Iterator<Item = (&'a DnsName, &'a DnsName)> {
+        // 有好几层的 NS 时只用离 qname 最近的那一层
+        let zone = self.get_delegation(qname);
         self.authorities
             .iter()
-            .filter_map(|record| match record {
+            .filter_map(move |record| match record {
                 //                                                          (com, e.gtld-servers.net)
-                DnsRecord::NS { domain, host, .. } => Some((domain.as_str(), host.as_str())),
-                _ => None
+                DnsRecord::NS { domain, host, .. } if Some(domain) == zone => Some((domain, host)),
+                _ => None,
             })
-            .filter(|(domain, _)| qname.ends_with(*domain))
     }
 
+    // 回复里委派到的下一层 zone, 比如问 com 的服务器 www.google.com 时是 google.com
+    pub fn get_delegation(&self, qname: &DnsName) -> Option<&DnsName> {
+        self.authorities
+            .iter()
+            .filter_map(|record| match record {
+                DnsRecord::NS { domain, .. } if qname.is_subdomain_of(domain) => Some(domain),
+                _ => None,
+            })
+            .max_by_key(|domain| domain.label_count())
+    }
+
+    // 只保留 zone 里面的记录, zone 是我们问的服务器负责的那一层
+    // 服务器不能对 zone 以外的名字给出答案, glue 或者 NS, 否则就可能是在投毒
+    pub fn retain_in_bailiwick(&mut self, zone: &DnsName) {
+        for section in [
+            &mut self.answers,
+            &mut self.authorities,
+            &mut self.resources,
+        ] {
+            section.retain(|record| {
+                let keep = record.domain().is_subdomain_of(zone);
+                if !keep {
+                    eprintln!(
+                        "Dropping out-of-bailiwick record for zone {:?}: {:?}",
+                        zone, record
+                    );
+                }
+                keep
+            });
+        }
+    }
 
     // ;; ADDITIONAL SECTION:
     // e.gtld-servers.net.	172800	IN	A	192.12.94.30
     // b.gtld-servers.net.	172800	IN	A	192.33.14.30
     // b.gtld-servers.net.	172800	IN	AAAA	2001:503:231d::2:30
     // j.gtld-servers.net.	172800	IN	A	192.48.79.30
-    pub fn get_resolved_ns(&self, qname: &str) -> Option<Ipv4Addr> {
-        self.get_ns(qname)
-            .flat_map(|(_, host)| {
-                self.resources
-                    .iter()
-                    .filter_map(move |record| match record {
-                                        // e.gtld-servers.net, 192.12.94.30
-                        DnsRecord::A { domain, addr, .. } if domain == host => Some(addr),
-                        _ => None,
-                    })
-            })
-            .map(|addr| *addr)
-            .next()
+    // 返回所有有 glue 的 NS 地址, 按 AUTHORITY SECTION 中的顺序, 一个不通就换下一个
+    // IPv4 的地址都排在 IPv6 前面, 没有 IPv6 网络时很快就会失败换下一个
+    pub fn get_resolved_ns(&self, qname: &DnsName) -> Vec<IpAddr> {
+        let mut ipv4 = Vec::new();
+        let mut ipv6 = Vec::new();
+        for (_, host) in self.get_ns(qname) {
+            for record in &self.resources {
+                match record {
+                    // e.gtld-servers.net, 192.12.94.30
+                    DnsRecord::A { domain, addr, .. } if domain == host => {
+                        ipv4.push(IpAddr::from(*addr))
+                    }
+                    // b.gtld-servers.net, 2001:503:231d::2:30
+                    DnsRecord::AAAA { domain, addr, .. } if domain == host => {
+                        ipv6.push(IpAddr::from(*addr))
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        ipv4.append(&mut ipv6);
+        ipv4
     }
 
-    pub fn get_unresolved_ns<'a>(&'a self, qname: &'a str) -> Option<&'a str> {
-        self.get_ns(qname)
-            .map(|(_, host)| host)
-            .next()
+    pub fn get_unresolved_ns<'a>(&'a self, qname: &'a DnsName) -> Vec<&'a DnsName> {
+        self.get_ns(qname).map(|(_, host)| host).collect()
     }
 }
-
-
@@ -0,0 +1,205 @@
+use crate::byte_packet_buffer::{DnsRecord, QueryType, ResultCode};
+use crate::dns_name::DnsName;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+// 上游给的 TTL 太大的话也只缓存这么久
+const MAX_TTL: u32 = 7 * 24 * 3600;
+
+struct CacheEntry {
+    records: Vec<DnsRecord>,
+    stored_at: Instant,
+    expires_at: Instant,
+}
+
+type Entries = HashMap<(DnsName, QueryType), CacheEntry>;
+
+fn lookup_entries(entries: &Entries, qname: &DnsName, qtype: QueryType) -> Option<Vec<DnsRecord>> {
+    let entry = entries.get(&(qname.clone(), qtype))?;
+
+    let now = Instant::now();
+    if now >= entry.expires_at {
+        return None;
+    }
+
+    let elapsed = now.duration_since(entry.stored_at).as_secs() as u32;
+    let records = entry
+        .records
+        .iter()
+        .map(|rec| {
+            let mut rec = rec.clone();
+            rec.set_ttl(rec.ttl().min(MAX_TTL).saturating_sub(elapsed));
+            rec
+        })
+        .collect();
+
+    Some(records)
+}
+
+// 按 (name, type) 分组, 每组整体替换掉之前的
+fn store_entries(entries: &mut Entries, records: &[DnsRecord]) {
+    let mut groups: HashMap<(DnsName, QueryType), Vec<DnsRecord>> = HashMap::new();
+    for rec in records {
+        let key = (rec.domain().clone(), rec.query_type());
+        let group = groups.entry(key).or_default();
+        if !group.contains(rec) {
+            group.push(rec.clone());
+        }
+    }
+
+    let now = Instant::now();
+    for (key, records) in groups {
+        let ttl = records.iter().map(|rec| rec.ttl()).min().unwrap_or(0);
+        if ttl == 0 {
+            continue;
+        }
+
+        entries.insert(
+            key,
+            CacheEntry {
+                records,
+                stored_at: now,
+                expires_at: now + Duration::from_secs(ttl.min(MAX_TTL) as u64),
+            },
+        );
+    }
+
+    entries.retain(|_, entry| entry.expires_at > now);
+}
+
+// 否定的结果, 带着 zone 的 SOA, 回复的时候要放在 AUTHORITY SECTION
+struct NegativeEntry {
+    soa: DnsRecord,
+    expires_at: Instant,
+}
+
+impl NegativeEntry {
+    fn soa(&self, now: Instant) -> Option<DnsRecord> {
+        if now >= self.expires_at {
+            return None;
+        }
+
+        let mut soa = self.soa.clone();
+        soa.set_ttl((self.expires_at - now).as_secs() as u32);
+        Some(soa)
+    }
+}
+
+// (name, type) -> 这个 name 下这个 type 的所有记录
+// 记录整体按最小的 TTL 过期, 取出来的时候 TTL 减去已经过去的时间
+//
+// 另外按 RFC 2308 缓存否定的结果:
+// NXDOMAIN 表示这个 name 什么 type 都没有, 所以只按 name 存
+// NODATA 表示 name 存在但没有这个 type, 按 (name, type) 存
+//
+// 委派里的 NS 和 glue 是上一层 zone 给的, 上一层对它们不是权威的
+// 单独存在 delegations 里, 只用来找下一层的服务器, 不能当成答案返回
+pub struct Cache {
+    entries: RwLock<Entries>,
+    delegations: RwLock<Entries>,
+    nxdomain: RwLock<HashMap<DnsName, NegativeEntry>>,
+    nodata: RwLock<HashMap<(DnsName, QueryType), NegativeEntry>>,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Cache {
+    pub fn new() -> Cache {
+        Cache {
+            entries: RwLock::new(HashMap::new()),
+            delegations: RwLock::new(HashMap::new()),
+            nxdomain: RwLock::new(HashMap::new()),
+            nodata: RwLock::new(HashMap::new()),
+        }
+    }
+
+    // DnsName 的比较不区分大小写, 所以 key 不用转成小写
+    pub fn lookup(&self, qname: &DnsName, qtype: QueryType) -> Option<Vec<DnsRecord>> {
+        lookup_entries(&self.entries.read().unwrap(), qname, qtype)
+    }
+
+    pub fn store(&self, records: &[DnsRecord]) {
+        store_entries(&mut self.entries.write().unwrap(), records);
+    }
+
+    // 委派里的 NS 和 glue, 以及回复里 ANSWER SECTION 以外的记录
+    pub fn store_delegation(&self, records: &[DnsRecord]) {
+        store_entries(&mut self.delegations.write().unwrap(), records);
+    }
+
+    // 先看权威的记录, 没有的话再看委派里的
+    pub fn lookup_delegation(&self, qname: &DnsName, qtype: QueryType) -> Option<Vec<DnsRecord>> {
+        self.lookup(qname, qtype)
+            .or_else(|| lookup_entries(&self.delegations.read().unwrap(), qname, qtype))
+    }
+
+    // 否定的结果能缓存多久: SOA 自己的 TTL 和 SOA MINIMUM 中小的那个
+    fn negative_ttl(soa: &DnsRecord) -> Option<u32> {
+        match soa {
+            DnsRecord::SOA { ttl, minimum, .. } => Some((*ttl).min(*minimum).min(MAX_TTL)),
+            _ => None,
+        }
+    }
+
+    // rescode 是 NXDOMAIN 或者 NOERROR (NODATA), 其他的不缓存
+    pub fn store_negative(
+        &self,
+        qname: &DnsName,
+        qtype: QueryType,
+        rescode: ResultCode,
+        soa: &DnsRecord,
+    ) {
+        let ttl = match Cache::negative_ttl(soa) {
+            Some(ttl) if ttl > 0 => ttl,
+            _ => return,
+        };
+
+        let now = Instant::now();
+        let entry = NegativeEntry {
+            soa: soa.clone(),
+            expires_at: now + Duration::from_secs(ttl as u64),
+        };
+
+        match rescode {
+            ResultCode::NXDOMAIN => {
+                let mut nxdomain = self.nxdomain.write().unwrap();
+                nxdomain.insert(qname.clone(), entry);
+                nxdomain.retain(|_, entry| entry.expires_at > now);
+            }
+            ResultCode::NOERROR => {
+                let mut nodata = self.nodata.write().unwrap();
+                nodata.insert((qname.clone(), qtype), entry);
+                nodata.retain(|_, entry| entry.expires_at > now);
+            }
+            _ => {}
+        }
+    }
+
+    // 返回 NXDOMAIN 或者 NOERROR (NODATA), 以及 TTL 已经减过的 SOA
+    pub fn lookup_negative(
+        &self,
+        qname: &DnsName,
+        qtype: QueryType,
+    ) -> Option<(ResultCode, DnsRecord)> {
+        let now = Instant::now();
+
+        if let Some(entry) = self.nxdomain.read().unwrap().get(qname) {
+            if let Some(soa) = entry.soa(now) {
+                return Some((ResultCode::NXDOMAIN, soa));
+            }
+        }
+
+        if let Some(entry) = self.nodata.read().unwrap().get(&(qname.clone(), qtype)) {
+            if let Some(soa) = entry.soa(now) {
+                return Some((ResultCode::NOERROR, soa));
+            }
+        }
+
+        None
+    }
+}
@@ -0,0 +1,285 @@
+use crate::error::{Error, Result};
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+
+// 整个名字在报文里最长 255 字节, 包括每个 label 前面的长度和最后的 0
+pub const MAX_NAME_LEN: usize = 255;
+// label 的长度只能用低 6 位, 前两位是压缩指针用的
+pub const MAX_LABEL_LEN: usize = 63;
+
+// 域名, 按 label 保存, 保留原来的大小写
+// 相等, hash 和排序都不区分大小写, 排序按 RFC 4034 的 canonical order
+// 根没有 label, 文本形式是空字符串
+#[derive(Clone, Default)]
+pub struct DnsName {
+    // www.example.com 是 [www, example, com]
+    labels: Vec<Vec<u8>>,
+}
+
+impl DnsName {
+    pub fn root() -> DnsName {
+        DnsName::default()
+    }
+
+    // label 不能为空, 每个最长 63 字节, 整个名字最长 255 字节
+    pub fn from_labels<I, L>(labels: I) -> Result<DnsName>
+    where
+        I: IntoIterator<Item = L>,
+        L: Into<Vec<u8>>,
+    {
+        let name = DnsName {
+            labels: labels.into_iter().map(Into::into).collect(),
+        };
+
+        for label in &name.labels {
+            if label.is_empty() {
+                return Err(Error::Syntax("empty label".to_string()));
+            }
+            if label.len() > MAX_LABEL_LEN {
+                return Err(Error::Syntax(format!(
+                    "label exceeds {} bytes",
+                    MAX_LABEL_LEN
+                )));
+            }
+        }
+        if name.wire_len() > MAX_NAME_LEN {
+            return Err(Error::Syntax(format!(
+                "name exceeds {} bytes",
+                MAX_NAME_LEN
+            )));
+        }
+
+        Ok(name)
+    }
+
+    // 从报文里读出来的, 长度在读的时候已经检查过了
+    pub(crate) fn from_wire_labels(labels: Vec<Vec<u8>>) -> DnsName {
+        DnsName { labels }
+    }
+
+    pub fn labels(&self) -> impl DoubleEndedIterator<Item = &[u8]> + ExactSizeIterator {
+        self.labels.iter().map(Vec::as_slice)
+    }
+
+    pub fn label_count(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    // 不压缩时在报文里占的字节数
+    pub fn wire_len(&self) -> usize {
+        self.labels
+            .iter()
+            .map(|label| label.len() + 1)
+            .sum::<usize>()
+            + 1
+    }
+
+    // 去掉最左边的 label, 根没有 parent
+    pub fn parent(&self) -> Option<DnsName> {
+        if self.is_root() {
+            return None;
+        }
+
+        Some(DnsName {
+            labels: self.labels[1..].to_vec(),
+        })
+    }
+
+    // 最右边的 count 个 label, 比如 www.example.com 的 suffix(2) 是 example.com
+    pub fn suffix(&self, count: usize) -> DnsName {
+        let start = self.labels.len().saturating_sub(count);
+        DnsName {
+            labels: self.labels[start..].to_vec(),
+        }
+    }
+
+    // 按 label 从右往左比较, 不区分大小写, 名字本身也算
+    // www.google.com 在 com 下面, 但是 evilcom 不在, 所有名字都在根下面
+    pub fn is_subdomain_of(&self, zone: &DnsName) -> bool {
+        zone.labels.len() <= self.labels.len()
+            && self
+                .labels
+                .iter()
+                .rev()
+                .zip(zone.labels.iter().rev())
+                .all(|(a, b)| a.eq_ignore_ascii_case(b))
+    }
+
+    // 每个字节都换一下, 长度不变, 比如换成小写或者 0x20 的随机大小写
+    pub fn map_bytes(&self, mut f: impl FnMut(u8) -> u8) -> DnsName {
+        DnsName {
+            labels: self
+                .labels
+                .iter()
+                .map(|label| label.iter().map(|&b| f(b)).collect())
+                .collect(),
+        }
+    }
+
+    pub fn to_lowercase(&self) -> DnsName {
+        self.map_bytes(|b| b.to_ascii_lowercase())
+    }
+}
+
+impl PartialEq for DnsName {
+    fn eq(&self, other: &DnsName) -> bool {
+        self.labels.len() == other.labels.len()
+            && self
+                .labels
+                .iter()
+                .zip(&other.labels)
+                .all(|(a, b)| a.eq_ignore_ascii_case(b))
+    }
+}
+
+impl Eq for DnsName {}
+
+// 和 eq 一致, 只看小写之后的字节
+impl Hash for DnsName {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_usize(self.labels.len());
+        for label in &self.labels {
+            state.write_usize(label.len());
+            for b in label {
+                state.write_u8(b.to_ascii_lowercase());
+            }
+        }
+    }
+}
+
+// RFC 4034 6.1: 从最右边的 label 开始比, 每个 label 按小写之后的字节比
+// 前面都一样时 label 少的排在前面, 比如 example < a.example < z.example
+impl Ord for DnsName {
+    fn cmp(&self, other: &DnsName) -> Ordering {
+        let mut ours = self.labels.iter().rev();
+        let mut theirs = other.labels.iter().rev();
+        loop {
+            match (ours.next(), theirs.next()) {
+                (None, None) => return Ordering::Equal,
+                (None, Some(_)) => return Ordering::Less,
+                (Some(_), None) => return Ordering::Greater,
+                (Some(a), Some(b)) => {
+                    let a = a.iter().map(u8::to_ascii_lowercase);
+                    let b = b.iter().map(u8::to_ascii_lowercase);
+                    match a.cmp(b) {
+                        Ordering::Equal => continue,
+                        ordering => return ordering,
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl PartialOrd for DnsName {
+    fn partial_cmp(&self, other: &DnsName) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// RFC 1035 的文本格式, 最后的点可以有也可以没有, 空字符串和 . 都是根
+// \. 是 label 里的点, \\ 是反斜杠, \DDD 是十进制的任意字节, 其他的 \X 就是 X 本身
+impl FromStr for DnsName {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<DnsName> {
+        if s.is_empty() || s == "." {
+            return Ok(DnsName::root());
+        }
+
+        let bytes = s.as_bytes();
+        let mut labels = Vec::new();
+        let mut label = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'.' => {
+                    labels.push(std::mem::take(&mut label));
+                    i += 1;
+                }
+                b'\\' => match &bytes[i + 1..] {
+                    [a, b, c, ..] if [a, b, c].iter().all(|d| d.is_ascii_digit()) => {
+                        let value = [a, b, c]
+                            .iter()
+                            .fold(0u32, |value, d| value * 10 + (**d - b'0') as u32);
+                        if value > 255 {
+                            return Err(Error::Syntax(format!(
+                                "escape \\{} is larger than 255",
+                                value
+                            )));
+                        }
+                        label.push(value as u8);
+                        i += 4;
+                    }
+                    [d, ..] if d.is_ascii_digit() => {
+                        return Err(Error::Syntax("\\DDD escape needs three digits".to_string()))
+                    }
+                    [c, ..] => {
+                        label.push(*c);
+                        i += 2;
+                    }
+                    [] => return Err(Error::Syntax("name ends with a backslash".to_string())),
+                },
+                b => {
+                    label.push(b);
+                    i += 1;
+                }
+            }
+        }
+
+        // 最后是没有转义的点时 label 是空的, 就是 fqdn 最后的那个点
+        if !label.is_empty() {
+            labels.push(label);
+        }
+
+        DnsName::from_labels(labels)
+    }
+}
+
+impl PartialEq<str> for DnsName {
+    fn eq(&self, other: &str) -> bool {
+        other.parse::<DnsName>().is_ok_and(|other| *self == other)
+    }
+}
+
+impl PartialEq<&str> for DnsName {
+    fn eq(&self, other: &&str) -> bool {
+        *self == **other
+    }
+}
+
+// 不带最后的点, 和原来用 String 的时候一样
+// label 里的点, 反斜杠和 zone 文件里有特殊含义的字符前面加 \, 不可见的字节用 \DDD
+// 这样输出的文本再 parse 回来和原来的名字一个字节都不差
+impl fmt::Display for DnsName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, label) in self.labels.iter().enumerate() {
+            if i > 0 {
+                f.write_str(".")?;
+            }
+            for &b in label {
+                match b {
+                    b'.' | b'\\' | b'"' | b'(' | b')' | b';' | b'@' | b'$' => {
+                        write!(f, "\\{}", b as char)?
+                    }
+                    0x21..=0x7e => write!(f, "{}", b as char)?,
+                    _ => write!(f, "\\{:03}", b)?,
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for DnsName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.to_string())
+    }
+}
@@ -0,0 +1,68 @@
+use std::fmt;
+use std::io;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// offset 都是出错时在报文中的字节位置
+#[derive(Debug)]
+pub enum Error {
+    // 报文在读完之前就结束了
+    Truncated { offset: usize },
+    // 写的时候超过了 buffer 的上限, 比如 UDP 的 512 字节
+    BufferFull { offset: usize },
+    BadLabel { offset: usize, reason: &'static str },
+    // 压缩指针跳了太多次, 基本是指针成环了
+    PointerLoop { offset: usize },
+    // 整个名字超过 255 字节
+    NameTooLong { offset: usize },
+    UnsupportedType { offset: usize, qtype: u16 },
+    // 其他格式错误, 比如 rdata 的长度对不上
+    Malformed { offset: usize, reason: &'static str },
+    // 文本格式解析失败, 比如 RFC 3597 的 \# 格式
+    Syntax(String),
+    // 一次查询用完了允许的委派次数, 嵌套深度或者上游查询次数
+    BudgetExceeded(&'static str),
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Truncated { offset } => write!(f, "End of buffer at offset {}", offset),
+            Error::BufferFull { offset } => write!(f, "Buffer full at offset {}", offset),
+            Error::BadLabel { offset, reason } => {
+                write!(f, "Bad label at offset {}: {}", offset, reason)
+            }
+            Error::PointerLoop { offset } => {
+                write!(f, "Too many compression jumps at offset {}", offset)
+            }
+            Error::NameTooLong { offset } => {
+                write!(f, "Name exceeds 255 bytes at offset {}", offset)
+            }
+            Error::UnsupportedType { offset, qtype } => {
+                write!(f, "Unsupported type {} at offset {}", qtype, offset)
+            }
+            Error::Malformed { offset, reason } => {
+                write!(f, "Malformed packet at offset {}: {}", offset, reason)
+            }
+            Error::Syntax(reason) => write!(f, "Syntax error: {}", reason),
+            Error::BudgetExceeded(what) => write!(f, "Too many {} for one query", what),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -1,2 +1,10 @@
-mod byte_packet_buffer;
-mod server_proxy;
+#![allow(clippy::upper_case_acronyms)]
+
+#[cfg(feature = "tokio")]
+pub mod async_proxy;
+pub mod byte_packet_buffer;
+pub mod cache;
+pub mod dns_name;
+pub mod error;
+pub mod root_hints;
+pub mod server_proxy;
@@ -1,7 +1,10 @@
-mod byte_packet_buffer;
-mod server_proxy;
-
-use std::net::UdpSocket;
+use dns_self::root_hints::RootHints;
+use dns_self::server_proxy::{self, Resolver};
+use std::env;
+use std::io::{self, ErrorKind};
+use std::net::{TcpListener, UdpSocket};
+use std::sync::Arc;
+use std::thread;
 
 // fn main() -> Result<(), Box<dyn std::error::Error>> {
 //     let mut f = File::open("response_packet.txt")?;
@@ -123,13 +126,75 @@ use std::net::UdpSocket;
 // nc -u -l 1053 > query_packet.txt
 // nc -u 8.8.8.8 53 < query_packet.txt > response_packet.txt
 
+// 同时在做递归查询的线程数, 大部分时间都在等上游, 所以比 CPU 核数多
+const UDP_WORKERS: usize = 16;
+// 排队等 worker 的 UDP 请求上限
+const UDP_QUEUE_SIZE: usize = 1024;
+const MAX_TCP_CONNECTIONS: usize = 64;
+
+// 先绑定 [::], Linux 上它默认也收 IPv4, 这时再绑定 0.0.0.0 会报 AddrInUse, 跳过就行
+// 系统默认只收 IPv6 的话两个都能绑定成功, 没有 IPv6 的话就只有 0.0.0.0
+const LISTEN_ADDRS: [&str; 2] = ["[::]:2053", "0.0.0.0:2053"];
+
+fn bind_all<T>(bind: impl Fn(&'static str) -> io::Result<T>) -> io::Result<Vec<T>> {
+    let mut sockets = Vec::new();
+    let mut last_error = None;
+
+    for addr in LISTEN_ADDRS {
+        match bind(addr) {
+            Ok(socket) => sockets.push(socket),
+            Err(e) if e.kind() == ErrorKind::AddrInUse && !sockets.is_empty() => {}
+            Err(e) => {
+                eprintln!("Failed to listen on {}: {}", addr, e);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    match last_error {
+        Some(e) if sockets.is_empty() => Err(e),
+        _ => Ok(sockets),
+    }
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let socket = UdpSocket::bind(("0.0.0.0", 2053))?;
+    let sockets = bind_all(UdpSocket::bind)?;
+    let listeners = bind_all(TcpListener::bind)?;
+
+    // 第一个参数可以指定 named.root 格式的根服务器文件
+    let resolver = match env::args().nth(1) {
+        Some(path) => Resolver::with_root_hints(RootHints::from_file(path)?),
+        None => Resolver::new(),
+    };
+    let resolver = Arc::new(resolver);
+
+    // priming 放在后台, 根服务器连不上时也不耽误启动, 这期间先用 hints 里的地址
+    let priming_resolver = resolver.clone();
+    thread::spawn(move || {
+        if let Err(e) = priming_resolver.prime() {
+            eprintln!("Priming failed, using root hints as is: {}", e);
+        }
+    });
+
+    let mut servers = Vec::new();
+    for listener in listeners {
+        let tcp_resolver = resolver.clone();
+        servers.push(thread::spawn(move || {
+            server_proxy::serve_tcp(listener, tcp_resolver, MAX_TCP_CONNECTIONS)
+        }));
+    }
+    for socket in sockets {
+        let udp_resolver = resolver.clone();
+        servers.push(thread::spawn(move || {
+            server_proxy::serve_udp(socket, udp_resolver, UDP_WORKERS, UDP_QUEUE_SIZE)
+        }));
+    }
 
-    loop {
-        match server_proxy::handle_query(&socket) {
-            Ok(_) => {}
-            Err(e) => eprintln!("An error occurred: {}", e),
+    for server in servers {
+        if let Err(e) = server.join().unwrap() {
+            eprintln!("An error occurred: {}", e);
         }
     }
+
+    Ok(())
 }
@@ -0,0 +1,181 @@
+use crate::byte_packet_buffer::{DnsPacket, DnsRecord, QueryClass};
+use crate::dns_name::DnsName;
+use crate::error::{Error, Result};
+use std::fs;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+// IANA 发布的 named.root, https://www.internic.net/domain/named.root
+const IANA_NAMED_ROOT: &str = include_str!("named.root");
+
+// 根服务器的 NS 和它们的地址
+#[derive(Clone, Debug)]
+pub struct RootHints {
+    servers: Vec<RootServer>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RootServer {
+    pub host: DnsName,
+    pub ipv4: Vec<Ipv4Addr>,
+    pub ipv6: Vec<Ipv6Addr>,
+}
+
+impl Default for RootHints {
+    fn default() -> Self {
+        Self::iana()
+    }
+}
+
+impl RootHints {
+    // 内置的 13 个根服务器
+    pub fn iana() -> RootHints {
+        RootHints::parse(IANA_NAMED_ROOT).unwrap()
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<RootHints> {
+        RootHints::parse(&fs::read_to_string(path)?)
+    }
+
+    // named.root 的格式:
+    // .                        3600000      NS    A.ROOT-SERVERS.NET.
+    // A.ROOT-SERVERS.NET.      3600000      A     198.41.0.4
+    // A.ROOT-SERVERS.NET.      3600000      AAAA  2001:503:ba3e::2:30
+    // TTL 和 class 可以省略, ; 后面是注释
+    pub fn parse(text: &str) -> Result<RootHints> {
+        let mut records = Vec::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or_default();
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() {
+                continue;
+            }
+
+            let error = |reason: &str| Error::Syntax(format!("line {}: {}", i + 1, reason));
+
+            let mut rest = &fields[1..];
+            let mut ttl = 0;
+            if let Some(n) = rest.first().and_then(|f| f.parse::<u32>().ok()) {
+                ttl = n;
+                rest = &rest[1..];
+            }
+            if rest.first().is_some_and(|f| f.eq_ignore_ascii_case("IN")) {
+                rest = &rest[1..];
+            }
+
+            let (rtype, rdata) = match rest {
+                [rtype, rdata] => (rtype.to_ascii_uppercase(), *rdata),
+                _ => return Err(error("expected a type and one rdata field")),
+            };
+            let name = |text: &str| {
+                text.parse::<DnsName>()
+                    .map_err(|e| error(&format!("bad name {:?}: {}", text, e)))
+            };
+            let domain = name(fields[0])?;
+            let class = QueryClass::IN;
+
+            let record = match rtype.as_str() {
+                "NS" => DnsRecord::NS {
+                    domain,
+                    host: name(rdata)?,
+                    class,
+                    ttl,
+                },
+                "A" => DnsRecord::A {
+                    domain,
+                    addr: rdata.parse().map_err(|_| error("bad IPv4 address"))?,
+                    class,
+                    ttl,
+                },
+                "AAAA" => DnsRecord::AAAA {
+                    domain,
+                    addr: rdata.parse().map_err(|_| error("bad IPv6 address"))?,
+                    class,
+                    ttl,
+                },
+                _ => return Err(error("only NS, A and AAAA records are allowed")),
+            };
+            records.push(record);
+        }
+
+        let hints = RootHints::from_records(&records);
+        if hints.ipv4_addrs().is_empty() && hints.ipv6_addrs().is_empty() {
+            return Err(Error::Syntax("no root server addresses".to_string()));
+        }
+
+        Ok(hints)
+    }
+
+    // 根的 NS 记录加上这些 NS 的地址, 没有地址的 NS 会被丢掉
+    fn from_records(records: &[DnsRecord]) -> RootHints {
+        let mut servers: Vec<RootServer> = Vec::new();
+
+        for record in records {
+            if let DnsRecord::NS { domain, host, .. } = record {
+                if domain.is_root() && !servers.iter().any(|s| s.host == *host) {
+                    servers.push(RootServer {
+                        host: host.clone(),
+                        ipv4: Vec::new(),
+                        ipv6: Vec::new(),
+                    });
+                }
+            }
+        }
+
+        for record in records {
+            match record {
+                DnsRecord::A { domain, addr, .. } => {
+                    if let Some(s) = servers.iter_mut().find(|s| s.host == *domain) {
+                        s.ipv4.push(*addr);
+                    }
+                }
+                DnsRecord::AAAA { domain, addr, .. } => {
+                    if let Some(s) = servers.iter_mut().find(|s| s.host == *domain) {
+                        s.ipv6.push(*addr);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        servers.retain(|s| !s.ipv4.is_empty() || !s.ipv6.is_empty());
+        RootHints { servers }
+    }
+
+    // 用 `. NS` 的 priming 查询的回复生成新的 hints
+    // 回复里没有可用的地址时返回 None, 继续用原来的
+    pub fn from_priming_response(response: &DnsPacket) -> Option<RootHints> {
+        let records: Vec<DnsRecord> = response
+            .answers
+            .iter()
+            .chain(response.resources.iter())
+            .cloned()
+            .collect();
+
+        let hints = RootHints::from_records(&records);
+        if hints.servers.is_empty() {
+            return None;
+        }
+
+        Some(hints)
+    }
+
+    pub fn servers(&self) -> &[RootServer] {
+        &self.servers
+    }
+
+    pub fn ipv4_addrs(&self) -> Vec<Ipv4Addr> {
+        self.servers
+            .iter()
+            .flat_map(|s| s.ipv4.iter().copied())
+            .collect()
+    }
+
+    pub fn ipv6_addrs(&self) -> Vec<Ipv6Addr> {
+        self.servers
+            .iter()
+            .flat_map(|s| s.ipv6.iter().copied())
+            .collect()
+    }
+}
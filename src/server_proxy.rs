@@ -1,85 +1,1180 @@
-use crate::byte_packet_buffer::{BytePacketBuffer, DnsPacket, DnsQuestion, QueryType, ResultCode};
-use std::net::{UdpSocket, Ipv4Addr};
+use crate::byte_packet_buffer::{
+    BytePacketBuffer, DnsPacket, DnsQuestion, DnsRecord, Edns, QueryClass, QueryType, ResultCode,
+    VectorPacketBuffer, TCP_MAX_SIZE, UDP_MAX_SIZE,
+};
+use crate::cache::Cache;
+use crate::dns_name::DnsName;
+use crate::error::{Error, Result};
+use crate::root_hints::RootHints;
+use rand::seq::SliceRandom;
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, TrySendError};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
-fn lookup(qname: &str, qtype: QueryType, server: (Ipv4Addr, u16)) -> Result<DnsPacket, Box<dyn std::error::Error>> {
-    let socket = UdpSocket::bind(("0.0.0.0", 43210))?;
+// 通过 EDNS 告诉对方我们能收多大的 UDP 包
+// 1232 是 DNS flag day 2020 推荐的值, 基本不会被 IP 分片
+pub(crate) const EDNS_UDP_PAYLOAD_SIZE: u16 = 1232;
 
+// TCP 连接上多久没有新的请求就关掉
+pub(crate) const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// 等上游服务器回复的时间, 第一轮 800ms, 之后每一轮翻倍
+const UPSTREAM_INITIAL_TIMEOUT: Duration = Duration::from_millis(800);
+// 所有 NS 都超时的话最多问几轮
+const UPSTREAM_ATTEMPTS: u32 = 3;
+
+pub(crate) fn query_packet(qname: &DnsName, qtype: QueryType) -> DnsPacket {
     let mut packet = DnsPacket::new();
-    packet.header.id = 6666;
+    // 每次用随机的 id, 让伪造回复的人猜不到
+    packet.header.id = rand::random();
     packet.header.questions = 1;
     packet.header.recursion_desired = true;
     packet
         .questioins
-        .push(DnsQuestion::new(qname.to_string(), qtype));
+        .push(DnsQuestion::new(qname.clone(), qtype));
+    packet.edns = Some(Edns::new(EDNS_UDP_PAYLOAD_SIZE));
+
+    packet
+}
+
+// 和上游同一个地址族的本地地址, 端口为 0 由系统分配
+pub(crate) fn local_addr_for(server: SocketAddr) -> SocketAddr {
+    match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    }
+}
+
+// DNS 0x20: 随机改变 qname 里每个字母的大小写, 服务器会原样带回来
+// 伪造回复的人除了 id 和端口还得猜中大小写
+pub(crate) fn randomize_case(qname: &DnsName) -> DnsName {
+    qname.map_bytes(|b| {
+        if rand::random() {
+            b.to_ascii_uppercase()
+        } else {
+            b.to_ascii_lowercase()
+        }
+    })
+}
+
+// 回复的 id 和 question 必须和我们发出去的一样, 不然就可能是伪造的
+// exact_case 时名字的大小写也要一模一样
+pub(crate) fn matches_query(query: &DnsPacket, response: &DnsPacket, exact_case: bool) -> bool {
+    if !response.header.response || response.header.id != query.header.id {
+        return false;
+    }
+
+    match (&query.questioins[..], &response.questioins[..]) {
+        ([q], [r]) => {
+            // DnsName 的 == 不区分大小写, 要逐个字节比较 label
+            let same_name = if exact_case {
+                q.name.labels().eq(r.name.labels())
+            } else {
+                q.name == r.name
+            };
+            same_name && q.qtype == r.qtype && q.class == r.class
+        }
+        _ => false,
+    }
+}
+
+// 发出去的 question, 打开 0x20 时名字的大小写是随机的
+pub(crate) fn outgoing_query(qname: &DnsName, qtype: QueryType, randomize: bool) -> DnsPacket {
+    if randomize {
+        query_packet(&randomize_case(qname), qtype)
+    } else {
+        query_packet(qname, qtype)
+    }
+}
+
+fn lookup_udp(
+    qname: &DnsName,
+    qtype: QueryType,
+    server: (IpAddr, u16),
+    timeout: Duration,
+    randomize_case: bool,
+) -> Result<DnsPacket> {
+    // 端口交给系统随机分配, 每次查询都是一个新的 socket
+    let server = SocketAddr::from(server);
+    let socket = UdpSocket::bind(local_addr_for(server))?;
 
+    let mut packet = outgoing_query(qname, qtype, randomize_case);
     let mut req_buffer = BytePacketBuffer::new();
     packet.write(&mut req_buffer)?;
-    let _s = socket.send_to(&req_buffer.buf[0..req_buffer.pos], server);
+    socket.send_to(&req_buffer.buf[0..req_buffer.pos], server)?;
 
-    let mut res_buffer = BytePacketBuffer::new();
-    let _r = socket.recv_from(&mut res_buffer.buf)?;
+    // 对不上的包直接丢掉继续等, 但总的等待时间不能超过 timeout
+    let deadline = Instant::now() + timeout;
+    let mut data = vec![0; EDNS_UDP_PAYLOAD_SIZE as usize];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(Error::Io(io::Error::new(
+                ErrorKind::TimedOut,
+                "no matching response from upstream",
+            )));
+        }
+        socket.set_read_timeout(Some(remaining))?;
+
+        let (size, src_addr) = socket.recv_from(&mut data)?;
+        if src_addr != server {
+            eprintln!("Ignoring datagram from {}, expected {}", src_addr, server);
+            continue;
+        }
 
-    DnsPacket::from_buffer(&mut res_buffer)
+        let mut res_buffer = VectorPacketBuffer::from_bytes(&data[..size]);
+        match DnsPacket::from_buffer(&mut res_buffer) {
+            Ok(response) if matches_query(&packet, &response, randomize_case) => {
+                return Ok(response)
+            }
+            Ok(response) => eprintln!(
+                "Ignoring mismatched response {} from {}",
+                response.header.id, server
+            ),
+            Err(e) => eprintln!("Ignoring malformed response from {}: {}", server, e),
+        }
+    }
 }
 
-fn recursive_lookup(qname: &str, qtype: QueryType) -> Result<DnsPacket, Box<dyn std::error::Error>> {
-    let mut ns ="198.41.0.4".parse::<Ipv4Addr>().unwrap();
+fn lookup_tcp(
+    qname: &DnsName,
+    qtype: QueryType,
+    server: (IpAddr, u16),
+    timeout: Duration,
+    randomize_case: bool,
+) -> Result<DnsPacket> {
+    let mut stream = TcpStream::connect_timeout(&SocketAddr::from(server), timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
 
-    loop {
-        println!("attemptin lookup of {:?} {} with ns {}", qtype, qname, ns);
+    let mut packet = outgoing_query(qname, qtype, randomize_case);
+    let mut req_buffer = VectorPacketBuffer::new();
+    packet.write(&mut req_buffer)?;
+    write_tcp_message(&mut stream, &req_buffer.buf)?;
 
-        let server = (ns, 53);
-        let response = lookup(qname, qtype, server)?;
+    let data = match read_tcp_message(&mut stream)? {
+        Some(data) => data,
+        None => {
+            return Err(Error::Io(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "connection closed before response",
+            )))
+        }
+    };
 
-        if !response.answers.is_empty() && response.header.rescode == ResultCode::NOERROR {
+    let mut res_buffer = VectorPacketBuffer::from_bytes(&data);
+    let response = DnsPacket::from_buffer(&mut res_buffer)?;
+    if !matches_query(&packet, &response, randomize_case) {
+        return Err(Error::Malformed {
+            offset: 0,
+            reason: "response does not match query",
+        });
+    }
+
+    Ok(response)
+}
+
+// 先走 UDP, 回复被截断 (TC) 了再用 TCP 重新问一遍
+pub fn lookup(
+    qname: &DnsName,
+    qtype: QueryType,
+    server: (IpAddr, u16),
+    timeout: Duration,
+) -> Result<DnsPacket> {
+    lookup_with(qname, qtype, server, timeout, false)
+}
+
+// randomize_case 时使用 0x20, 回复里 question 的大小写对不上的都当成伪造的丢掉
+pub fn lookup_with(
+    qname: &DnsName,
+    qtype: QueryType,
+    server: (IpAddr, u16),
+    timeout: Duration,
+    randomize_case: bool,
+) -> Result<DnsPacket> {
+    let response = lookup_udp(qname, qtype, server, timeout, randomize_case)?;
+    if response.header.truncated_message {
+        println!("truncated response from {:?}, retrying over tcp", server);
+        return lookup_tcp(qname, qtype, server, timeout, randomize_case);
+    }
+
+    Ok(response)
+}
+
+pub(crate) fn is_timeout(e: &Error) -> bool {
+    matches!(e, Error::Io(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock))
+}
+
+// 按顺序问每一个 NS, 超时的在下一轮用加倍的等待时间再问
+// 其他错误, 或者回了 SERVFAIL/REFUSED 的, 就不再问这一台了
+// 都失败的话优先返回最后一个收到的回复, 没有回复才返回错误
+// 同步和异步的 lookup_servers 共用这部分状态
+pub(crate) struct Attempts {
+    pending: Vec<(IpAddr, u16)>,
+    next: usize,
+    round: u32,
+    timeout: Duration,
+    timed_out: Vec<(IpAddr, u16)>,
+    last_response: Option<DnsPacket>,
+    last_error: Option<Error>,
+}
+
+impl Attempts {
+    pub(crate) fn new(servers: &[(IpAddr, u16)]) -> Attempts {
+        Attempts {
+            pending: servers.to_vec(),
+            next: 0,
+            round: 0,
+            timeout: UPSTREAM_INITIAL_TIMEOUT,
+            timed_out: Vec::new(),
+            last_response: None,
+            last_error: None,
+        }
+    }
+
+    // 下一个要问的 server 和这次的等待时间, None 表示都试完了
+    pub(crate) fn next_server(&mut self) -> Option<((IpAddr, u16), Duration)> {
+        if self.next == self.pending.len() {
+            self.round += 1;
+            if self.timed_out.is_empty() || self.round == UPSTREAM_ATTEMPTS {
+                return None;
+            }
+            self.pending = std::mem::take(&mut self.timed_out);
+            self.next = 0;
+            self.timeout *= 2;
+        }
+
+        let server = self.pending[self.next];
+        self.next += 1;
+        Some((server, self.timeout))
+    }
+
+    // 记录一次查询的结果, 拿到可以用的回复时返回它
+    pub(crate) fn record(
+        &mut self,
+        qname: &DnsName,
+        server: (IpAddr, u16),
+        result: Result<DnsPacket>,
+    ) -> Option<DnsPacket> {
+        match result {
+            Ok(response)
+                if matches!(
+                    response.header.rescode,
+                    ResultCode::SERVFAIL | ResultCode::REFUSED
+                ) =>
+            {
+                eprintln!(
+                    "{:?} from {:?} for {}, trying next server",
+                    response.header.rescode, server, qname
+                );
+                self.last_response = Some(response);
+                None
+            }
+            Ok(response) => Some(response),
+            Err(e) => {
+                eprintln!("lookup of {} on {:?} failed: {}", qname, server, e);
+                if is_timeout(&e) {
+                    self.timed_out.push(server);
+                }
+                self.last_error = Some(e);
+                None
+            }
+        }
+    }
+
+    pub(crate) fn finish(self) -> Result<DnsPacket> {
+        match (self.last_response, self.last_error) {
+            (Some(response), _) => Ok(response),
+            (None, Some(e)) => Err(e),
+            (None, None) => Err(Error::Io(io::Error::new(
+                ErrorKind::NotFound,
+                "no nameserver to query",
+            ))),
+        }
+    }
+}
+
+pub fn lookup_servers(
+    qname: &DnsName,
+    qtype: QueryType,
+    servers: &[(IpAddr, u16)],
+) -> Result<DnsPacket> {
+    let mut budget = Budget::new(Limits::default());
+    lookup_servers_within(qname, qtype, servers, false, &mut budget)
+}
+
+// 每发一次查询都要从 budget 里扣
+fn lookup_servers_within(
+    qname: &DnsName,
+    qtype: QueryType,
+    servers: &[(IpAddr, u16)],
+    randomize_case: bool,
+    budget: &mut Budget,
+) -> Result<DnsPacket> {
+    let mut attempts = Attempts::new(servers);
+    while let Some((server, timeout)) = attempts.next_server() {
+        budget.spend_query()?;
+        let result = lookup_with(qname, qtype, server, timeout, randomize_case);
+        if let Some(response) = attempts.record(qname, server, result) {
             return Ok(response);
         }
+    }
+
+    attempts.finish()
+}
+
+// 一次客户端查询最多能做多少事, 包括为了找 NS 地址而做的嵌套查询
+// 超过任何一项都直接返回 SERVFAIL
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    // 最多跟多少次委派
+    pub max_referrals: u32,
+    // 查 NS 地址时最多嵌套几层
+    pub max_ns_depth: u32,
+    // 最多向上游发多少个查询, 包括重试
+    pub max_upstream_queries: u32,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_referrals: 24,
+            max_ns_depth: 4,
+            max_upstream_queries: 64,
+        }
+    }
+}
+
+// priming 失败了还可以接着用 hints, 不值得像普通查询那样把所有根都试几轮
+pub(crate) const PRIMING_LIMITS: Limits = Limits {
+    max_referrals: 0,
+    max_ns_depth: 0,
+    max_upstream_queries: 4,
+};
+
+// 一次查询剩下的额度, 同步和异步共用
+pub(crate) struct Budget {
+    limits: Limits,
+    referrals: u32,
+    depth: u32,
+    queries: u32,
+}
+
+impl Budget {
+    pub(crate) fn new(limits: Limits) -> Budget {
+        Budget {
+            limits,
+            referrals: 0,
+            depth: 0,
+            queries: 0,
+        }
+    }
+
+    pub(crate) fn spend_query(&mut self) -> Result<()> {
+        if self.queries >= self.limits.max_upstream_queries {
+            return Err(Error::BudgetExceeded("upstream queries"));
+        }
+        self.queries += 1;
+        Ok(())
+    }
+
+    pub(crate) fn spend_referral(&mut self) -> Result<()> {
+        if self.referrals >= self.limits.max_referrals {
+            return Err(Error::BudgetExceeded("referrals"));
+        }
+        self.referrals += 1;
+        Ok(())
+    }
+
+    // 进入一层 NS 地址的查询, 查完要调用 leave_ns_lookup
+    pub(crate) fn enter_ns_lookup(&mut self) -> Result<()> {
+        if self.depth >= self.limits.max_ns_depth {
+            return Err(Error::BudgetExceeded("nested nameserver lookups"));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    pub(crate) fn leave_ns_lookup(&mut self) {
+        self.depth -= 1;
+    }
+}
+
+// CNAME 链最多跟几层
+const MAX_CNAME_CHAIN: usize = 8;
+
+// 跟踪一次查询中经过的 CNAME, 同步和异步共用
+pub(crate) struct CnameChain {
+    question: DnsQuestion,
+    // 已经查过的名字, 用来发现环
+    names: Vec<DnsName>,
+    // 之前的回复里的 CNAME, 最后拼到回复的最前面
+    records: Vec<DnsRecord>,
+}
+
+impl CnameChain {
+    pub(crate) fn new(qname: &DnsName, qtype: QueryType) -> CnameChain {
+        CnameChain {
+            question: DnsQuestion::new(qname.clone(), qtype),
+            names: vec![qname.clone()],
+            records: Vec::new(),
+        }
+    }
+
+    // 从当前的名字开始沿着回复里的 CNAME 往下走
+    // 走到的名字在回复里没有要的记录, 就返回它, 需要从头再查一次
+    pub(crate) fn follow(&mut self, response: &DnsPacket) -> Option<DnsName> {
+        let qtype = self.question.qtype;
+        if qtype == QueryType::CNAME || response.header.rescode != ResultCode::NOERROR {
+            return None;
+        }
+
+        let mut current = self.names.last().unwrap().clone();
+        let mut followed = Vec::new();
+        loop {
+            let answers = response
+                .answers
+                .iter()
+                .filter(|rec| *rec.domain() == current);
+            let mut cname = None;
+            for rec in answers {
+                match rec {
+                    DnsRecord::CNAME { host, .. } => cname = Some((rec, host)),
+                    _ if rec.query_type() == qtype => return None,
+                    _ => {}
+                }
+            }
+
+            let (rec, target) = cname?;
+            if self.names.contains(target) {
+                eprintln!("CNAME loop at {} for {}", target, self.question.name);
+                return None;
+            }
+            if self.names.len() > MAX_CNAME_CHAIN {
+                eprintln!("CNAME chain too long for {}", self.question.name);
+                return None;
+            }
+
+            followed.push(rec.clone());
+            self.names.push(target.clone());
+            current = target.clone();
+
+            // 这个回复里还有下一层的话继续在回复里找
+            if !response.answers.iter().any(|rec| *rec.domain() == current) {
+                self.records.extend(followed);
+                return Some(current);
+            }
+        }
+    }
+
+    // 把前面经过的 CNAME 放到最终回复的 answers 前面
+    pub(crate) fn finish(self, mut response: DnsPacket) -> DnsPacket {
+        if !self.records.is_empty() {
+            let mut answers = self.records;
+            answers.append(&mut response.answers);
+            response.answers = answers;
+        }
+        response.questioins = vec![self.question];
+
+        response
+    }
+}
+
+// 递归查询中收到一个回复之后下一步要做什么
+enum Step<'a> {
+    // 这个回复就是最终结果
+    Done,
+    // 下一层的 NS 带了 glue, 直接问这些地址
+    Referral(&'a DnsName, Vec<IpAddr>),
+    // 下一层的 NS 没有 glue, 要先查出它们的地址
+    Unresolved(&'a DnsName, Vec<&'a DnsName>),
+}
+
+// QNAME minimisation (RFC 9156): 每次只问比已知部分多一层的名字, 用 A 查询
+// 上面的服务器就看不到完整的名字和类型
+struct Minimiser {
+    // 已经确认到这一层为止没有新的 zone, None 表示不省略, 直接问完整的名字
+    known: Option<DnsName>,
+}
+
+impl Minimiser {
+    fn new(enabled: bool, zone: &DnsName) -> Minimiser {
+        Minimiser {
+            known: enabled.then(|| zone.clone()),
+        }
+    }
+
+    // 这次要问的名字, 已经到 qname 本身时返回 None
+    fn next_name(&self, qname: &DnsName) -> Option<DnsName> {
+        let depth = self.known.as_ref()?.label_count() + 1;
+        (qname.label_count() > depth).then(|| qname.suffix(depth))
+    }
+
+    // 问到的名字下面没有 zone cut, 下次再多问一层
+    fn no_cut(&mut self, name: &DnsName) {
+        if let Some(known) = &mut self.known {
+            *known = name.clone();
+        }
+    }
+
+    // 进了新的 zone, 从这个 zone 开始往下数
+    fn zone_cut(&mut self, zone: &DnsName) {
+        self.no_cut(zone);
+    }
+
+    // 有的服务器对空的中间节点 (empty non-terminal) 回 NXDOMAIN 或者别的错误
+    // 这时不再省略, 剩下的都问完整的名字
+    fn give_up(&mut self) {
+        self.known = None;
+    }
+}
+
+// resolve 的下一步, 由同步或者异步的循环去做 IO
+pub(crate) enum Action {
+    // 向这些服务器问这个名字
+    Query(DnsName, QueryType, Vec<(IpAddr, u16)>),
+    // 委派没有 glue, 先查出这个 NS 的地址
+    LookupNs(DnsName, QueryType),
+    Done(DnsPacket),
+}
+
+// 把一个名字查到底的过程, 不管 CNAME
+// 每一步该问谁, 收到回复之后怎么走都在这里决定, 同步和异步共用
+pub(crate) struct Resolution {
+    qname: DnsName,
+    qtype: QueryType,
+    // 当前在问的服务器负责的 zone 和它们的地址
+    zone: DnsName,
+    servers: Vec<IpAddr>,
+    minimiser: Minimiser,
+    // 这次问的是省略过的名字
+    minimised: Option<DnsName>,
+    // 没有 glue 的 NS 名字, 每个先查 A 再查 AAAA
+    // 查到的地址都不能用时接着查下一个
+    unresolved: VecDeque<(DnsName, QueryType)>,
+    // 剩下的 NS 都用不了时的结果, 没有 glue 的委派或者最后一次失败
+    fallback: Option<Result<DnsPacket>>,
+}
+
+impl Resolution {
+    pub(crate) fn new(resolver: &Resolver, qname: &DnsName, qtype: QueryType) -> Resolution {
+        let (zone, servers) = resolver.start_servers(qname);
+        Resolution {
+            qname: qname.clone(),
+            qtype,
+            minimiser: resolver.minimiser(&zone),
+            zone,
+            servers,
+            minimised: None,
+            unresolved: VecDeque::new(),
+            fallback: None,
+        }
+    }
+
+    // 缓存里有就直接返回, 没有的话问当前的服务器
+    pub(crate) fn next_query(&mut self, resolver: &Resolver) -> Action {
+        if let Some(packet) = resolver.cached(&self.qname, self.qtype) {
+            return Action::Done(packet);
+        }
+
+        self.minimised = self.minimiser.next_name(&self.qname);
+        let (ask, ask_type) = match &self.minimised {
+            Some(name) => (name.clone(), QueryType::A),
+            None => (self.qname.clone(), self.qtype),
+        };
+
+        println!(
+            "attemptin lookup of {:?} {} with ns {:?}",
+            ask_type, ask, self.servers
+        );
+
+        let port = resolver.upstream_port;
+        let candidates = self.servers.iter().map(|&ns| (ns, port)).collect();
+        Action::Query(ask, ask_type, candidates)
+    }
+
+    // 收到 Action::Query 的结果
+    pub(crate) fn answered(
+        &mut self,
+        resolver: &Resolver,
+        result: Result<DnsPacket>,
+        budget: &mut Budget,
+    ) -> Result<Action> {
+        let failed = match &result {
+            Err(Error::BudgetExceeded(_)) => false,
+            Err(_) => true,
+            Ok(response) => matches!(
+                response.header.rescode,
+                ResultCode::SERVFAIL | ResultCode::REFUSED
+            ),
+        };
+        // 这一个 NS 的地址都不能用, 换下一个没有 glue 的 NS
+        if failed && !self.unresolved.is_empty() {
+            self.minimised = None;
+            self.fallback = Some(result);
+            return self.next_ns_lookup();
+        }
+
+        let mut response = result?;
+        let minimised = self.minimised.take();
+        let was_minimised = minimised.is_some();
+
+        if was_minimised && response.header.rescode != ResultCode::NOERROR {
+            self.minimiser.give_up();
+            return Ok(self.next_query(resolver));
+        }
+
+        let (ask, ask_type) = match minimised {
+            Some(name) => (name, QueryType::A),
+            None => (self.qname.clone(), self.qtype),
+        };
+        match resolver.next_step(&ask, ask_type, &self.zone, &mut response) {
+            Step::Done if was_minimised => {
+                self.minimiser.no_cut(&ask);
+                return Ok(self.next_query(resolver));
+            }
+            Step::Done => return Ok(Action::Done(response)),
+            Step::Referral(delegation, resolved) => {
+                budget.spend_referral()?;
+                self.zone = delegation.clone();
+                self.servers = resolved;
+                self.unresolved.clear();
+                self.fallback = None;
+            }
+            Step::Unresolved(delegation, new_ns_names) => {
+                budget.spend_referral()?;
+                self.zone = delegation.clone();
+                self.servers = Vec::new();
+                // 查不到的就换下一个, 先查 A, 没有的话再查 AAAA
+                self.unresolved = new_ns_names
+                    .into_iter()
+                    .flat_map(|name| {
+                        [
+                            (name.clone(), QueryType::A),
+                            (name.clone(), QueryType::AAAA),
+                        ]
+                    })
+                    .collect();
+                self.minimiser.zone_cut(&self.zone);
+                self.fallback = Some(Ok(response));
+                return self.next_ns_lookup();
+            }
+        }
+        self.minimiser.zone_cut(&self.zone);
+
+        Ok(self.next_query(resolver))
+    }
+
+    fn next_ns_lookup(&mut self) -> Result<Action> {
+        match self.unresolved.pop_front() {
+            Some((name, qtype)) => Ok(Action::LookupNs(name, qtype)),
+            None => self
+                .fallback
+                .take()
+                .unwrap_or_else(|| Ok(DnsPacket::new()))
+                .map(Action::Done),
+        }
+    }
+
+    // 收到 Action::LookupNs 的结果, 额度用完了就不用再试别的 NS 了
+    pub(crate) fn ns_resolved(
+        &mut self,
+        resolver: &Resolver,
+        ns_name: &DnsName,
+        result: Result<DnsPacket>,
+    ) -> Result<Action> {
+        match result {
+            Ok(response) => self.servers = response.get_addrs(),
+            Err(e @ Error::BudgetExceeded(_)) => return Err(e),
+            Err(e) => eprintln!("failed to resolve ns {}: {}", ns_name, e),
+        }
+
+        if self.servers.is_empty() {
+            return self.next_ns_lookup();
+        }
+
+        // 查到了地址, 这个 NS 的 AAAA 就不用查了, 别的 NS 留着失败时再查
+        self.unresolved.retain(|(name, _)| name != ns_name);
+        Ok(self.next_query(resolver))
+    }
+}
+
+pub struct Resolver {
+    cache: Cache,
+    // 启动时的 priming 查询会更新它, 所以放在锁里
+    root_hints: RwLock<RootHints>,
+    limits: Limits,
+    qname_minimisation: bool,
+    case_randomization: bool,
+    upstream_port: u16,
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Resolver {
+    pub fn new() -> Resolver {
+        Resolver::with_root_hints(RootHints::iana())
+    }
+
+    // 用自己的根服务器, 比如实验室或者隔离网络里的
+    pub fn with_root_hints(root_hints: RootHints) -> Resolver {
+        Resolver {
+            cache: Cache::new(),
+            root_hints: RwLock::new(root_hints),
+            limits: Limits::default(),
+            qname_minimisation: false,
+            case_randomization: false,
+            upstream_port: 53,
+        }
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Resolver {
+        self.limits = limits;
+        self
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    // 打开 QNAME minimisation, 默认关闭
+    pub fn with_qname_minimisation(mut self, enabled: bool) -> Resolver {
+        self.qname_minimisation = enabled;
+        self
+    }
+
+    // 打开 DNS 0x20, 默认关闭
+    // 有少数服务器不会原样带回 question 的大小写, 打开之后问它们会一直超时
+    pub fn with_case_randomization(mut self, enabled: bool) -> Resolver {
+        self.case_randomization = enabled;
+        self
+    }
+
+    pub fn case_randomization(&self) -> bool {
+        self.case_randomization
+    }
+
+    // 所有上游服务器都用这个端口, 默认 53, 测试时可以指向本地的假服务器
+    pub fn with_upstream_port(mut self, port: u16) -> Resolver {
+        self.upstream_port = port;
+        self
+    }
+
+    fn minimiser(&self, zone: &DnsName) -> Minimiser {
+        Minimiser::new(self.qname_minimisation, zone)
+    }
+
+    pub fn root_hints(&self) -> RootHints {
+        self.root_hints.read().unwrap().clone()
+    }
+
+    // 根服务器的地址, 打乱顺序让每个根都分担一些查询
+    // IPv4 在前, IPv6 在后, 没有 IPv6 网络时很快就会失败换下一个
+    fn root_servers(&self) -> Vec<IpAddr> {
+        let root_hints = self.root_hints.read().unwrap();
+        let mut ipv4 = root_hints.ipv4_addrs();
+        let mut ipv6 = root_hints.ipv6_addrs();
+        ipv4.shuffle(&mut rand::thread_rng());
+        ipv6.shuffle(&mut rand::thread_rng());
+
+        ipv4.into_iter()
+            .map(IpAddr::from)
+            .chain(ipv6.into_iter().map(IpAddr::from))
+            .collect()
+    }
+
+    pub(crate) fn priming_servers(&self) -> Vec<(IpAddr, u16)> {
+        self.root_servers()
+            .into_iter()
+            .map(|ns| (ns, self.upstream_port))
+            .collect()
+    }
+
+    // 用 priming 查询的回复替换掉当前的 hints
+    pub(crate) fn apply_priming(&self, response: &DnsPacket) -> Result<()> {
+        match RootHints::from_priming_response(response) {
+            Some(root_hints) => {
+                println!("primed {} root servers", root_hints.servers().len());
+                *self.root_hints.write().unwrap() = root_hints;
+                Ok(())
+            }
+            None => Err(Error::Malformed {
+                offset: 0,
+                reason: "priming response has no root server addresses",
+            }),
+        }
+    }
+
+    // 向 hints 里的根服务器查询 `. NS`, 拿到最新的根服务器列表
+    pub fn prime(&self) -> Result<()> {
+        let response = lookup_servers_within(
+            &DnsName::root(),
+            QueryType::NS,
+            &self.priming_servers(),
+            false,
+            &mut Budget::new(PRIMING_LIMITS),
+        )?;
+        self.apply_priming(&response)
+    }
+
+    pub fn cache(&self) -> &Cache {
+        &self.cache
+    }
+
+    // 从 qname 开始往上找, 用缓存里离 qname 最近的那一层 NS 开始, 而不是每次都从根开始
+    // 返回这一层所有 NS 已知的地址
+    fn closest_cached_ns(&self, qname: &DnsName) -> (DnsName, Vec<IpAddr>) {
+        let mut domain = qname.clone();
+        loop {
+            let hosts = self
+                .cache
+                .lookup_delegation(&domain, QueryType::NS)
+                .unwrap_or_default();
+            let addrs: Vec<IpAddr> = hosts
+                .iter()
+                .filter_map(|rec| match rec {
+                    DnsRecord::NS { host, .. } => Some(host),
+                    _ => None,
+                })
+                .flat_map(|host| {
+                    let ipv4 = self.cache.lookup_delegation(host, QueryType::A);
+                    let ipv6 = self.cache.lookup_delegation(host, QueryType::AAAA);
+                    ipv4.into_iter().chain(ipv6).flatten()
+                })
+                .filter_map(|rec| match rec {
+                    DnsRecord::A { addr, .. } => Some(IpAddr::from(addr)),
+                    DnsRecord::AAAA { addr, .. } => Some(IpAddr::from(addr)),
+                    _ => None,
+                })
+                .collect();
+            if !addrs.is_empty() {
+                return (domain, addrs);
+            }
+
+            match domain.parent() {
+                Some(parent) => domain = parent,
+                None => return (DnsName::root(), Vec::new()),
+            }
+        }
+    }
+
+    fn cached_answer(&self, qname: &DnsName, qtype: QueryType) -> Option<DnsPacket> {
+        let mut packet = DnsPacket::new();
+        packet.header.response = true;
+        packet
+            .questioins
+            .push(DnsQuestion::new(qname.clone(), qtype));
+
+        if let Some(answers) = self.cache.lookup(qname, qtype) {
+            packet.answers = answers;
+            return Some(packet);
+        }
+
+        // 缓存了别名的话先返回 CNAME, 由 CnameChain 接着查目标
+        if qtype != QueryType::CNAME {
+            if let Some(answers) = self.cache.lookup(qname, QueryType::CNAME) {
+                packet.answers = answers;
+                return Some(packet);
+            }
+        }
+
+        // 否定的结果也要带上 SOA, 下游的 resolver 才能继续缓存
+        let (rescode, soa) = self.cache.lookup_negative(qname, qtype)?;
+        packet.header.rescode = rescode;
+        packet.authorities.push(soa);
+
+        Some(packet)
+    }
+
+    fn store_negative(&self, qname: &DnsName, qtype: QueryType, response: &DnsPacket) {
+        if let Some(soa) = response.get_soa() {
+            self.cache
+                .store_negative(qname, qtype, response.header.rescode, soa);
+        }
+    }
+
+    // 从缓存里最近的一层 NS 开始, 没有的话从根开始
+    // 返回这些服务器负责的 zone 和它们的地址
+    fn start_servers(&self, qname: &DnsName) -> (DnsName, Vec<IpAddr>) {
+        let (zone, servers) = self.closest_cached_ns(qname);
+        if servers.is_empty() {
+            return (DnsName::root(), self.root_servers());
+        }
+        (zone, servers)
+    }
+
+    fn cached(&self, qname: &DnsName, qtype: QueryType) -> Option<DnsPacket> {
+        let packet = self.cached_answer(qname, qtype)?;
+        println!("cache hit for {:?} {}", qtype, qname);
+        Some(packet)
+    }
+
+    // 去掉 zone 以外的记录之后缓存回复里的记录, 并决定下一步怎么走
+    // zone 是这次问的服务器负责的那一层
+    fn next_step<'a>(
+        &self,
+        qname: &'a DnsName,
+        qtype: QueryType,
+        zone: &DnsName,
+        response: &'a mut DnsPacket,
+    ) -> Step<'a> {
+        response.retain_in_bailiwick(zone);
+        let response = &*response;
+
+        // 只有 ANSWER SECTION 是权威的答案, 委派的 NS 和 glue 只用来找下一层的服务器
+        self.cache.store(&response.answers);
+        self.cache.store_delegation(&response.authorities);
+        self.cache.store_delegation(&response.resources);
+
+        if !response.answers.is_empty() && response.header.rescode == ResultCode::NOERROR {
+            return Step::Done;
+        }
 
         if response.header.rescode == ResultCode::NXDOMAIN {
-            return Ok(response);
+            self.store_negative(qname, qtype, response);
+            return Step::Done;
+        }
+
+        // NODATA: name 存在但是没有这个 type, 带的是 SOA 而不是下一层的 NS
+        if response.header.rescode == ResultCode::NOERROR
+            && response.answers.is_empty()
+            && response.get_soa().is_some()
+        {
+            self.store_negative(qname, qtype, response);
+            return Step::Done;
         }
 
+        // 只接受往下一层的委派, 同一层或者往上的委派会让查询原地打转
+        let delegation = match response.get_delegation(qname) {
+            Some(delegation) if delegation.label_count() > zone.label_count() => delegation,
+            _ => return Step::Done,
+        };
+
         // 解析 AUTHORITY SECTION 中的 NS, 并从 ADDITIONAL SECTION 拿到该 NS 的 addr
-        if let Some(new_ns)= response.get_resolved_ns(qname) {
-            ns = new_ns;
-            continue;
+        let resolved = response.get_resolved_ns(qname);
+        if !resolved.is_empty() {
+            return Step::Referral(delegation, resolved);
         }
 
         // 如果没有从 ADDITIONAL SECTION 拿到该 NS 的 addr
-        // 重新从 AUTHORITY SECTION 中的 NS 中拿一个
-        // 并再一次发起请求, 请求该 NS 的 addr
-        let new_ns_name = match response.get_unresolved_ns(qname) {
-            Some(x) => x,
-            None => return Ok(response),
-        };
+        // 依次对 AUTHORITY SECTION 中的 NS 发起请求, 请求该 NS 的 addr
+        Step::Unresolved(delegation, response.get_unresolved_ns(qname))
+    }
 
-        // 如果得到了该 NS 的 addr 继续使用该 addr 进行循环
-        let recursize_response = recursive_lookup(&new_ns_name, QueryType::A)?;
-        if let Some(new_ns) = recursize_response.get_random_a() {
-            ns = new_ns;
-        } else {
-            return Ok(response);
+    // 查询 qname, 回复只有 CNAME 的话继续查 CNAME 的目标
+    pub fn recursive_lookup(&self, qname: &DnsName, qtype: QueryType) -> Result<DnsPacket> {
+        self.lookup_within(qname, qtype, &mut Budget::new(self.limits))
+    }
+
+    // 嵌套的 NS 查询和 CNAME 的目标都用同一个 budget
+    fn lookup_within(
+        &self,
+        qname: &DnsName,
+        qtype: QueryType,
+        budget: &mut Budget,
+    ) -> Result<DnsPacket> {
+        let mut chain = CnameChain::new(qname, qtype);
+        let mut response = self.resolve(qname, qtype, budget)?;
+        while let Some(target) = chain.follow(&response) {
+            response = self.resolve(&target, qtype, budget)?;
+        }
+
+        Ok(chain.finish(response))
+    }
+
+    // 不管 CNAME, 只把 qname 本身查到底
+    fn resolve(&self, qname: &DnsName, qtype: QueryType, budget: &mut Budget) -> Result<DnsPacket> {
+        let mut resolution = Resolution::new(self, qname, qtype);
+        let mut action = resolution.next_query(self);
+        loop {
+            action = match action {
+                Action::Query(ask, ask_type, servers) => {
+                    let result = lookup_servers_within(
+                        &ask,
+                        ask_type,
+                        &servers,
+                        self.case_randomization,
+                        budget,
+                    );
+                    resolution.answered(self, result, budget)?
+                }
+                Action::LookupNs(ns_name, ns_qtype) => {
+                    budget.enter_ns_lookup()?;
+                    let result = self.lookup_within(&ns_name, ns_qtype, budget);
+                    budget.leave_ns_lookup();
+                    resolution.ns_resolved(self, &ns_name, result)?
+                }
+                Action::Done(response) => return Ok(response),
+            };
         }
     }
 }
 
-pub fn handle_query(socket: &UdpSocket) -> Result<(), Box<dyn std::error::Error>> {
-    let mut req_buffer = BytePacketBuffer::new();
-    let (_size, src_addr) = socket.recv_from(&mut req_buffer.buf)?;
-    let mut request_packet = DnsPacket::from_buffer(&mut req_buffer)?;
+// TCP 上每个消息前面有 2 字节的长度
+// 对方正常关闭连接时返回 None
+fn read_tcp_message(stream: &mut TcpStream) -> Result<Option<Vec<u8>>> {
+    let mut len_buf = [0; 2];
+    match stream.read_exact(&mut len_buf) {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let len = u16::from_be_bytes(len_buf) as usize;
+    let mut data = vec![0; len];
+    stream.read_exact(&mut data)?;
+
+    Ok(Some(data))
+}
+
+fn write_tcp_message(stream: &mut TcpStream, data: &[u8]) -> Result<()> {
+    if data.len() > TCP_MAX_SIZE {
+        return Err(Error::BufferFull {
+            offset: TCP_MAX_SIZE,
+        });
+    }
+
+    let mut message = Vec::with_capacity(data.len() + 2);
+    message.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    message.extend_from_slice(data);
+    stream.write_all(&message)?;
+
+    Ok(())
+}
+
+// UDP 和 TCP 共用, 只负责从请求得到回复
+// version.bind CH TXT 这类常见的运维探测
+fn chaos_answer(question: &DnsQuestion) -> Option<DnsRecord> {
+    let version = question.name == "version.bind" || question.name == "version.server";
+    match (question.class, question.qtype) {
+        (QueryClass::CH, QueryType::TXT) if version => Some(DnsRecord::TXT {
+            domain: question.name.clone(),
+            data: vec![format!("dns_self {}", env!("CARGO_PKG_VERSION")).into_bytes()],
+            class: QueryClass::CH,
+            ttl: 0,
+        }),
+        _ => None,
+    }
+}
+
+// 解析失败时从原始字节里尽量拿出 id 和 opcode, 回一个 FORMERR
+// 连 id 都没有, 或者本身就是一个回复, 就不回了
+fn format_error_response(data: &[u8]) -> Option<DnsPacket> {
+    if data.len() < 2 || (data.len() > 2 && data[2] & 0x80 != 0) {
+        return None;
+    }
 
+    let mut response_packet = DnsPacket::new();
+    response_packet.header.id = u16::from_be_bytes([data[0], data[1]]);
+    if data.len() > 2 {
+        response_packet.header.opcode = (data[2] >> 3) & 0x0F;
+        response_packet.header.recursion_desired = data[2] & 0x01 > 0;
+    }
+    response_packet.header.recursion_available = true;
+    response_packet.header.response = true;
+    response_packet.header.rescode = ResultCode::FORMERR;
+
+    Some(response_packet)
+}
+
+// 解析请求, 失败时给出 FORMERR 的回复
+// 返回 None 表示这个报文直接丢掉
+pub(crate) fn parse_request(
+    data: &[u8],
+    client: SocketAddr,
+) -> Option<std::result::Result<DnsPacket, DnsPacket>> {
+    match DnsPacket::from_buffer(&mut VectorPacketBuffer::from_bytes(data)) {
+        // 别人发来的回复不处理, 免得两个服务器互相回
+        Ok(request_packet) if request_packet.header.response => None,
+        Ok(request_packet) => Some(Ok(request_packet)),
+        Err(e) => {
+            eprintln!("Malformed request from {}: {}", client, e);
+            format_error_response(data).map(Err)
+        }
+    }
+}
+
+// 不需要递归查询的请求在这里就得到了回复
+// 需要查询的返回回复的框架和要查的 question
+pub(crate) enum Prepared {
+    Done(DnsPacket),
+    Resolve(DnsPacket, DnsQuestion),
+}
+
+pub(crate) fn prepare_response(mut request_packet: DnsPacket, client: SocketAddr) -> Prepared {
     let mut response_packet = DnsPacket::new();
 
     response_packet.header.id = request_packet.header.id;
-    response_packet.header.recursion_desired = true;
+    response_packet.header.opcode = request_packet.header.opcode;
+    response_packet.header.recursion_desired = request_packet.header.recursion_desired;
     response_packet.header.recursion_available = true;
     response_packet.header.response = true;
 
-    if let Some(question) = request_packet.questioins.pop() {
-        println!("Received query: {:?}", question);
+    // 客户端带了 OPT, 回复里也要带上
+    if request_packet.edns.is_some() {
+        response_packet.edns = Some(Edns::new(EDNS_UDP_PAYLOAD_SIZE));
+    }
+
+    // 只实现了标准查询, NOTIFY/UPDATE 之类都回 NOTIMP
+    if request_packet.header.opcode != 0 {
+        eprintln!(
+            "Unsupported opcode {} from {}",
+            request_packet.header.opcode, client
+        );
+        response_packet.header.rescode = ResultCode::NOTIMP;
+        response_packet.questioins = request_packet.questioins;
+        return Prepared::Done(response_packet);
+    }
+
+    // 一个请求里只允许有一个 question
+    if request_packet.questioins.len() != 1 {
+        eprintln!(
+            "Request from {} has {} questions",
+            client,
+            request_packet.questioins.len()
+        );
+        response_packet.header.rescode = ResultCode::FORMERR;
+        return Prepared::Done(response_packet);
+    }
+
+    let question = request_packet.questioins.remove(0);
+    println!("Received query from {}: {:?}", client, question);
+
+    if question.class != QueryClass::IN {
+        // 只对 IN 做递归, 其他 class 除了版本号的探测都拒绝
+        match chaos_answer(&question) {
+            Some(rec) => response_packet.answers.push(rec),
+            None => response_packet.header.rescode = ResultCode::REFUSED,
+        }
+    } else if is_zone_transfer(question.qtype) {
+        // 递归服务器没有 zone 可以传
+        eprintln!("Refused zone transfer of {} from {}", question.name, client);
+        response_packet.header.rescode = ResultCode::REFUSED;
+    } else {
+        return Prepared::Resolve(response_packet, question);
+    }
+    response_packet.questioins.push(question);
 
-        if let Ok(result) = recursive_lookup(&question.name, question.qtype) {
-            response_packet.questioins.push(question);
+    Prepared::Done(response_packet)
+}
+
+// 把递归查询的结果填进回复
+pub(crate) fn finish_response(
+    mut response_packet: DnsPacket,
+    question: DnsQuestion,
+    result: Result<DnsPacket>,
+    client: SocketAddr,
+) -> DnsPacket {
+    match result {
+        Ok(result) => {
             response_packet.header.rescode = result.header.rescode;
 
             for rec in result.answers {
@@ -94,20 +1189,214 @@ pub fn handle_query(socket: &UdpSocket) -> Result<(), Box<dyn std::error::Error>
                 println!("Resource: {:?}", rec);
                 response_packet.resources.push(rec);
             }
-        } else {
+        }
+        Err(e) => {
+            eprintln!(
+                "Failed to resolve {} {:?} for {}: {}",
+                question.name, question.qtype, client, e
+            );
+            response_packet.header.rescode = ResultCode::SERVFAIL;
+        }
+    }
+    response_packet.questioins.push(question);
+
+    response_packet
+}
+
+fn build_response(resolver: &Resolver, request_packet: DnsPacket, client: SocketAddr) -> DnsPacket {
+    match prepare_response(request_packet, client) {
+        Prepared::Done(response_packet) => response_packet,
+        Prepared::Resolve(response_packet, question) => {
+            let result = resolver.recursive_lookup(&question.name, question.qtype);
+            finish_response(response_packet, question, result, client)
+        }
+    }
+}
+
+// AXFR 和 IXFR
+fn is_zone_transfer(qtype: QueryType) -> bool {
+    matches!(qtype, QueryType::UNKNOWN(251) | QueryType::UNKNOWN(252))
+}
+
+// 没有 EDNS 的客户端只能收 512 字节, 有的话取双方都能接受的大小
+pub(crate) fn udp_response_size(request_packet: &DnsPacket) -> usize {
+    match request_packet.edns {
+        Some(ref edns) => {
+            (edns.udp_payload_size as usize).clamp(UDP_MAX_SIZE, EDNS_UDP_PAYLOAD_SIZE as usize)
+        }
+        None => UDP_MAX_SIZE,
+    }
+}
+
+pub fn handle_query(socket: &UdpSocket, resolver: &Resolver) -> Result<()> {
+    let mut data = vec![0; EDNS_UDP_PAYLOAD_SIZE as usize];
+    let (size, src_addr) = socket.recv_from(&mut data)?;
+
+    handle_datagram(socket, resolver, &data[..size], src_addr)
+}
+
+// 处理一个已经收到的 UDP 请求, 回复从同一个 socket 发回去
+pub fn handle_datagram(
+    socket: &UdpSocket,
+    resolver: &Resolver,
+    data: &[u8],
+    src_addr: SocketAddr,
+) -> Result<()> {
+    let (mut response_packet, max_size) = match parse_request(data, src_addr) {
+        Some(Ok(request_packet)) => {
+            let max_size = udp_response_size(&request_packet);
+            (build_response(resolver, request_packet, src_addr), max_size)
+        }
+        Some(Err(response_packet)) => (response_packet, UDP_MAX_SIZE),
+        None => return Ok(()),
+    };
+
+    let res_data = encode_response(&mut response_packet, max_size)?;
+    socket.send_to(&res_data, src_addr)?;
+
+    Ok(())
+}
+
+// 写失败时 buffer 里只有一半的记录, header 里的数量却是全部的, 不能直接发出去
+pub(crate) fn encode_response(response_packet: &mut DnsPacket, max_size: usize) -> Result<Vec<u8>> {
+    let mut res_buffer = VectorPacketBuffer::with_limit(max_size);
+    match response_packet.write(&mut res_buffer) {
+        Ok(()) => return Ok(res_buffer.buf),
+        // 放不下就只回 header 和 question, 设置 TC 让客户端改用 TCP
+        Err(Error::BufferFull { .. }) => response_packet.header.truncated_message = true,
+        // 记录本身写不出来, 比如超长的 TXT, 只能回 SERVFAIL
+        Err(e) => {
+            eprintln!("Failed to encode response: {}", e);
             response_packet.header.rescode = ResultCode::SERVFAIL;
         }
-    } else {
-        response_packet.header.rescode = ResultCode::FORMERR;
     }
+    response_packet.answers.clear();
+    response_packet.authorities.clear();
+    response_packet.resources.clear();
 
-    let mut res_buffer = BytePacketBuffer::new();
+    let mut res_buffer = VectorPacketBuffer::with_limit(max_size);
     response_packet.write(&mut res_buffer)?;
+    Ok(res_buffer.buf)
+}
 
-    let len = res_buffer.pos();
-    let data = res_buffer.get_range(0, len)?;
+// 一个连接上可以连续发多个请求, 直到客户端关闭或者空闲超时
+pub fn handle_tcp_connection(mut stream: TcpStream, resolver: &Resolver) -> Result<()> {
+    stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT))?;
+    let peer_addr = stream.peer_addr()?;
 
-    socket.send_to(data, src_addr)?;
+    loop {
+        let data = match read_tcp_message(&mut stream) {
+            Ok(Some(data)) => data,
+            Ok(None) => return Ok(()),
+            // 空闲超时, 和异步版本一样直接关掉连接, 不算错误
+            Err(e) if is_timeout(&e) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        let mut response_packet = match parse_request(&data, peer_addr) {
+            Some(Ok(request_packet)) => build_response(resolver, request_packet, peer_addr),
+            Some(Err(response_packet)) => response_packet,
+            None => continue,
+        };
+
+        let res_data = encode_response(&mut response_packet, TCP_MAX_SIZE)?;
+        write_tcp_message(&mut stream, &res_data)?;
+    }
+}
+
+// 收包的线程只负责把请求放进队列, 由 workers 个线程去做递归查询
+// 队列满了说明处理不过来, 新的请求直接丢掉, 客户端会自己重试
+pub fn serve_udp(
+    socket: UdpSocket,
+    resolver: Arc<Resolver>,
+    workers: usize,
+    queue_size: usize,
+) -> Result<()> {
+    let socket = Arc::new(socket);
+    let (sender, receiver) = mpsc::sync_channel::<(Vec<u8>, SocketAddr)>(queue_size);
+    let receiver = Arc::new(Mutex::new(receiver));
+
+    for _ in 0..workers {
+        let socket = socket.clone();
+        let resolver = resolver.clone();
+        let receiver = receiver.clone();
+        thread::spawn(move || loop {
+            // 拿到请求就马上释放锁, 别的 worker 才能取下一个
+            let next = receiver.lock().unwrap().recv();
+            let (data, src_addr) = match next {
+                Ok(query) => query,
+                Err(_) => return,
+            };
+            if let Err(e) = handle_datagram(&socket, &resolver, &data, src_addr) {
+                eprintln!("An error occurred handling {}: {}", src_addr, e);
+            }
+        });
+    }
+
+    loop {
+        let mut data = vec![0; EDNS_UDP_PAYLOAD_SIZE as usize];
+        let (size, src_addr) = match socket.recv_from(&mut data) {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("An error occurred: {}", e);
+                continue;
+            }
+        };
+        data.truncate(size);
+
+        match sender.try_send((data, src_addr)) {
+            Ok(_) => {}
+            Err(TrySendError::Full(_)) => {
+                eprintln!("Query queue full, dropping query from {}", src_addr)
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                return Err(Error::Io(io::Error::other("all workers exited")))
+            }
+        }
+    }
+}
+
+// 连接结束时把计数减回去
+struct ConnectionGuard(Arc<AtomicUsize>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// 每个 TCP 连接一个线程, 同时最多 max_connections 个, 超过的直接关掉
+pub fn serve_tcp(
+    listener: TcpListener,
+    resolver: Arc<Resolver>,
+    max_connections: usize,
+) -> Result<()> {
+    let active = Arc::new(AtomicUsize::new(0));
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("An error occurred on tcp: {}", e);
+                continue;
+            }
+        };
+
+        if active.fetch_add(1, Ordering::SeqCst) >= max_connections {
+            active.fetch_sub(1, Ordering::SeqCst);
+            eprintln!("Too many tcp connections, closing {:?}", stream.peer_addr());
+            continue;
+        }
+
+        let guard = ConnectionGuard(active.clone());
+        let resolver = resolver.clone();
+        thread::spawn(move || {
+            let _guard = guard;
+            if let Err(e) = handle_tcp_connection(stream, &resolver) {
+                eprintln!("An error occurred on tcp: {}", e);
+            }
+        });
+    }
 
     Ok(())
 }
@@ -0,0 +1,88 @@
+#![cfg(feature = "tokio")]
+
+use dns_self::async_proxy;
+use dns_self::byte_packet_buffer::{
+    BytePacketBuffer, DnsPacket, DnsQuestion, DnsRecord, QueryClass, QueryType, ResultCode,
+    VectorPacketBuffer,
+};
+use dns_self::server_proxy::Resolver;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+#[tokio::test]
+async fn lookup_over_tokio_sockets() {
+    let upstream = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+    let port = upstream.local_addr().unwrap().port();
+
+    let server = tokio::spawn(async move {
+        let mut data = [0; 512];
+        let (size, client) = upstream.recv_from(&mut data).await.unwrap();
+        let request =
+            DnsPacket::from_buffer(&mut VectorPacketBuffer::from_bytes(&data[..size])).unwrap();
+
+        let mut packet = DnsPacket::new();
+        packet.header.id = request.header.id;
+        packet.header.response = true;
+        packet.questioins = request.questioins.clone();
+        packet.answers.push(DnsRecord::A {
+            domain: "example.com".parse().unwrap(),
+            addr: Ipv4Addr::new(1, 2, 3, 4),
+            class: QueryClass::IN,
+            ttl: 60,
+        });
+
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        upstream
+            .send_to(&buffer.buf[..buffer.pos], client)
+            .await
+            .unwrap();
+    });
+
+    let response = async_proxy::lookup(
+        &"example.com".parse().unwrap(),
+        QueryType::A,
+        (Ipv4Addr::LOCALHOST.into(), port),
+        Duration::from_secs(5),
+    )
+    .await
+    .unwrap();
+    server.await.unwrap();
+
+    assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(1, 2, 3, 4)));
+}
+
+#[tokio::test]
+async fn serve_udp_answers_queries() {
+    let socket = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async_proxy::serve_udp(
+        socket,
+        Arc::new(Resolver::new()),
+        16,
+    ));
+
+    let mut question = DnsQuestion::new("version.server".parse().unwrap(), QueryType::TXT);
+    question.class = QueryClass::CH;
+    let mut packet = DnsPacket::new();
+    packet.header.id = 42;
+    packet.questioins.push(question);
+    let mut buffer = VectorPacketBuffer::new();
+    packet.write(&mut buffer).unwrap();
+
+    let client = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+    client.send_to(&buffer.buf, addr).await.unwrap();
+    let mut data = [0; 512];
+    let (size, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut data))
+        .await
+        .unwrap()
+        .unwrap();
+
+    let response =
+        DnsPacket::from_buffer(&mut VectorPacketBuffer::from_bytes(&data[..size])).unwrap();
+    assert_eq!(response.header.id, 42);
+    assert_eq!(response.header.rescode, ResultCode::NOERROR);
+    assert_eq!(response.answers.len(), 1);
+}
@@ -0,0 +1,115 @@
+use dns_self::byte_packet_buffer::{DnsRecord, QueryClass, QueryType, ResultCode};
+use dns_self::cache::Cache;
+use dns_self::dns_name::DnsName;
+use std::net::Ipv4Addr;
+use std::thread;
+use std::time::Duration;
+
+fn name(text: &str) -> DnsName {
+    text.parse().unwrap()
+}
+
+fn a(domain: &str, last: u8, ttl: u32) -> DnsRecord {
+    DnsRecord::A {
+        domain: domain.parse().unwrap(),
+        addr: Ipv4Addr::new(192, 0, 2, last),
+        class: QueryClass::IN,
+        ttl,
+    }
+}
+
+#[test]
+fn stores_record_sets_by_name_and_type() {
+    let cache = Cache::new();
+    cache.store(&[
+        a("example.com", 1, 300),
+        a("example.com", 2, 300),
+        a("www.example.com", 3, 300),
+        DnsRecord::NS {
+            domain: "example.com".parse().unwrap(),
+            host: "ns1.example.com".parse().unwrap(),
+            class: QueryClass::IN,
+            ttl: 3600,
+        },
+    ]);
+
+    let records = cache.lookup(&name("EXAMPLE.com"), QueryType::A).unwrap();
+    assert_eq!(
+        records,
+        vec![a("example.com", 1, 300), a("example.com", 2, 300)]
+    );
+    assert_eq!(
+        cache
+            .lookup(&name("example.com"), QueryType::NS)
+            .unwrap()
+            .len(),
+        1
+    );
+    assert!(cache.lookup(&name("example.com"), QueryType::MX).is_none());
+    assert!(cache.lookup(&name("example.org"), QueryType::A).is_none());
+}
+
+#[test]
+fn ttl_counts_down_and_expires() {
+    let cache = Cache::new();
+    cache.store(&[
+        a("example.com", 1, 1),
+        a("example.net", 1, 300),
+        a("example.org", 1, 0),
+    ]);
+    assert!(cache.lookup(&name("example.org"), QueryType::A).is_none());
+
+    thread::sleep(Duration::from_millis(1100));
+
+    assert!(cache.lookup(&name("example.com"), QueryType::A).is_none());
+    let records = cache.lookup(&name("example.net"), QueryType::A).unwrap();
+    assert_eq!(records[0].ttl(), 299);
+}
+
+fn soa(ttl: u32, minimum: u32) -> DnsRecord {
+    DnsRecord::SOA {
+        domain: "example.com".parse().unwrap(),
+        mname: "ns1.example.com".parse().unwrap(),
+        rname: "hostmaster.example.com".parse().unwrap(),
+        serial: 2024010101,
+        refresh: 7200,
+        retry: 3600,
+        expire: 1209600,
+        minimum,
+        class: QueryClass::IN,
+        ttl,
+    }
+}
+
+#[test]
+fn nxdomain_covers_every_type_and_nodata_only_one() {
+    let cache = Cache::new();
+    cache.store_negative(
+        &name("typo.example.com"),
+        QueryType::A,
+        ResultCode::NXDOMAIN,
+        &soa(3600, 300),
+    );
+    cache.store_negative(
+        &name("example.com"),
+        QueryType::AAAA,
+        ResultCode::NOERROR,
+        &soa(60, 300),
+    );
+
+    let (rescode, cached) = cache
+        .lookup_negative(&name("typo.example.com"), QueryType::MX)
+        .unwrap();
+    assert_eq!(rescode, ResultCode::NXDOMAIN);
+    // TTL 取 SOA TTL 和 MINIMUM 中小的
+    assert!(cached.ttl() > 290 && cached.ttl() <= 300);
+
+    let (rescode, cached) = cache
+        .lookup_negative(&name("example.com"), QueryType::AAAA)
+        .unwrap();
+    assert_eq!(rescode, ResultCode::NOERROR);
+    assert!(cached.ttl() > 50 && cached.ttl() <= 60);
+    assert!(cache
+        .lookup_negative(&name("example.com"), QueryType::A)
+        .is_none());
+}
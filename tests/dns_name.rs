@@ -0,0 +1,145 @@
+use dns_self::byte_packet_buffer::{DnsPacket, DnsQuestion, QueryType, VectorPacketBuffer};
+use dns_self::dns_name::DnsName;
+
+fn name(text: &str) -> DnsName {
+    text.parse().unwrap()
+}
+
+#[test]
+fn case_is_kept_but_ignored_when_comparing() {
+    let mixed = name("WWW.Example.COM");
+    assert_eq!(mixed.to_string(), "WWW.Example.COM");
+    assert_eq!(mixed, name("www.example.com"));
+    assert_eq!(mixed, "www.example.com.");
+    assert_eq!(mixed.to_lowercase().to_string(), "www.example.com");
+}
+
+#[test]
+fn labels_and_parents() {
+    let www = name("www.example.com");
+    let labels: Vec<&[u8]> = www.labels().collect();
+    assert_eq!(labels, [&b"www"[..], b"example", b"com"]);
+    assert_eq!(www.parent(), Some(name("example.com")));
+    assert_eq!(www.suffix(1), name("com"));
+    assert_eq!(www.suffix(5), www);
+
+    assert!(name(".").is_root());
+    assert_eq!(name(""), DnsName::root());
+    assert_eq!(DnsName::root().parent(), None);
+}
+
+#[test]
+fn subdomains_follow_label_boundaries() {
+    assert!(name("www.google.com").is_subdomain_of(&name("com")));
+    assert!(name("com").is_subdomain_of(&name("com")));
+    assert!(name("WWW.Example.COM").is_subdomain_of(&name("example.com")));
+    assert!(name("anything").is_subdomain_of(&DnsName::root()));
+    assert!(!name("evilcom").is_subdomain_of(&name("com")));
+    assert!(!name("ample.com").is_subdomain_of(&name("example.com")));
+    assert!(!name("com").is_subdomain_of(&name("google.com")));
+}
+
+// RFC 4034 6.1 里的例子
+#[test]
+fn canonical_ordering() {
+    let mut names: Vec<DnsName> = [
+        "Z.a.example",
+        "zABC.a.EXAMPLE",
+        "a.example",
+        "yljkjljk.a.example",
+        "z.example",
+        "\\200.z.example",
+        "*.z.example",
+        "\\001.z.example",
+        "example",
+    ]
+    .iter()
+    .map(|text| name(text))
+    .collect();
+    names.sort();
+
+    let sorted: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+    assert_eq!(
+        sorted,
+        [
+            "example",
+            "a.example",
+            "yljkjljk.a.example",
+            "Z.a.example",
+            "zABC.a.EXAMPLE",
+            "z.example",
+            "\\001.z.example",
+            "*.z.example",
+            "\\200.z.example",
+        ]
+    );
+}
+
+#[test]
+fn invalid_names_are_rejected() {
+    assert!("www..example.com".parse::<DnsName>().is_err());
+    assert!(".example.com".parse::<DnsName>().is_err());
+    assert!("a".repeat(64).parse::<DnsName>().is_err());
+    assert!("a".repeat(63).parse::<DnsName>().is_ok());
+
+    // 4 个 63 字节的 label 在报文里是 257 字节
+    let long = vec!["a".repeat(63); 4].join(".");
+    assert!(long.parse::<DnsName>().is_err());
+    let longest = format!("{}.{}", vec!["a".repeat(63); 3].join("."), "a".repeat(61));
+    assert_eq!(name(&longest).wire_len(), 255);
+}
+
+#[test]
+fn escapes_in_presentation_format() {
+    let dotted = name("a\\.b.example");
+    assert_eq!(dotted.label_count(), 2);
+    assert_eq!(dotted.labels().next(), Some(&b"a.b"[..]));
+    assert_eq!(dotted.to_string(), "a\\.b.example");
+
+    let binary = name("\\000\\255\\\\\\\".example");
+    assert_eq!(binary.labels().next(), Some(&[0, 255, b'\\', b'"'][..]));
+    assert_eq!(binary.to_string(), "\\000\\255\\\\\\\".example");
+
+    // \X 就是 X, \DDD 可以表示可见字符
+    assert_eq!(name("\\a\\098c"), name("abc"));
+    // 转义的点不是最后的点
+    assert_eq!(name("example\\.").label_count(), 1);
+
+    assert!("bad\\256".parse::<DnsName>().is_err());
+    assert!("bad\\12".parse::<DnsName>().is_err());
+    assert!("bad\\".parse::<DnsName>().is_err());
+}
+
+// 报文里的名字 -> 文本 -> 名字 -> 报文, 每个字节都不能变
+#[test]
+fn wire_names_survive_text_round_trips() {
+    let original = DnsName::from_labels([
+        b"with.dot".to_vec(),
+        b"back\\slash".to_vec(),
+        vec![0, 1, b' ', 0x7f, 0x80, 0xff],
+        "caf\u{e9}".as_bytes().to_vec(),
+        b"MiXeD".to_vec(),
+    ])
+    .unwrap();
+
+    let mut packet = DnsPacket::new();
+    packet
+        .questioins
+        .push(DnsQuestion::new(original.clone(), QueryType::A));
+    let mut buffer = VectorPacketBuffer::new();
+    packet.write(&mut buffer).unwrap();
+    let wire = buffer.buf.clone();
+
+    let read = DnsPacket::from_buffer(&mut VectorPacketBuffer::from_bytes(&wire)).unwrap();
+    let text = read.questioins[0].name.to_string();
+    let reparsed = name(&text);
+    assert!(reparsed.labels().eq(original.labels()));
+
+    let mut packet = DnsPacket::new();
+    packet
+        .questioins
+        .push(DnsQuestion::new(reparsed, QueryType::A));
+    let mut buffer = VectorPacketBuffer::new();
+    packet.write(&mut buffer).unwrap();
+    assert_eq!(buffer.buf, wire);
+}
@@ -0,0 +1,573 @@
+use dns_self::byte_packet_buffer::{
+    parse_generic_rdata, BytePacketBuffer, DnsPacket, DnsQuestion, DnsRecord, Edns, EdnsOption,
+    PacketBuffer, QueryClass, QueryType, ResultCode, VectorPacketBuffer,
+};
+use dns_self::dns_name::DnsName;
+use dns_self::error::Error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+fn name(text: &str) -> DnsName {
+    text.parse().unwrap()
+}
+
+fn round_trip(packet: &mut DnsPacket) -> (DnsPacket, usize) {
+    let mut buffer = BytePacketBuffer::new();
+    packet.write(&mut buffer).unwrap();
+    let len = buffer.pos();
+
+    buffer.pos = 0;
+    (DnsPacket::from_buffer(&mut buffer).unwrap(), len)
+}
+
+#[test]
+fn compressed_names_read_back() {
+    let mut packet = DnsPacket::new();
+    packet.header.id = 1234;
+    packet.questioins.push(DnsQuestion::new(
+        "google.com".parse().unwrap(),
+        QueryType::NS,
+    ));
+    for i in 1..=4 {
+        packet.answers.push(DnsRecord::NS {
+            domain: "google.com".parse().unwrap(),
+            host: format!("ns{}.google.com", i).parse().unwrap(),
+            class: QueryClass::IN,
+            ttl: 3600,
+        });
+        packet.resources.push(DnsRecord::A {
+            domain: format!("ns{}.google.com", i).parse().unwrap(),
+            addr: Ipv4Addr::new(216, 239, 32, 10 + i),
+            class: QueryClass::IN,
+            ttl: 3600,
+        });
+    }
+    packet.answers.push(DnsRecord::MX {
+        domain: "GOOGLE.com".parse().unwrap(),
+        priority: 10,
+        host: "smtp.google.com".parse().unwrap(),
+        class: QueryClass::IN,
+        ttl: 300,
+    });
+
+    let (parsed, len) = round_trip(&mut packet);
+
+    // header 12 + question 16 + 每条记录的名字都只剩一个 label 或一个指针
+    // GOOGLE.com 和 google.com 大小写不一样, 只有 com 能压缩
+    assert_eq!(len, 12 + 16 + 4 * 18 + (21 + 7) + 4 * 16);
+    assert_eq!(parsed.questioins, packet.questioins);
+    assert_eq!(parsed.resources, packet.resources);
+    assert_eq!(parsed.answers, packet.answers);
+    assert_eq!(parsed.answers[4].domain().to_string(), "GOOGLE.com");
+}
+
+#[test]
+fn root_name_is_a_single_zero() {
+    let mut packet = DnsPacket::new();
+    packet
+        .questioins
+        .push(DnsQuestion::new("".parse().unwrap(), QueryType::NS));
+
+    let (parsed, len) = round_trip(&mut packet);
+
+    assert_eq!(len, 12 + 1 + 4);
+    assert_eq!(parsed.questioins, packet.questioins);
+}
+
+#[test]
+fn name_case_is_preserved() {
+    let mut packet = DnsPacket::new();
+    packet.questioins.push(DnsQuestion::new(
+        "wWw.ExAmPle.CoM".parse().unwrap(),
+        QueryType::A,
+    ));
+
+    let (parsed, _) = round_trip(&mut packet);
+
+    assert_eq!(parsed.questioins[0].name, "wWw.ExAmPle.CoM");
+}
+
+#[test]
+fn edns_round_trip() {
+    let mut edns = Edns::new(1232);
+    edns.extended_rcode = 1;
+    edns.dnssec_ok = true;
+    edns.options.push(EdnsOption::Cookie {
+        client: vec![1, 2, 3, 4, 5, 6, 7, 8],
+        server: vec![],
+    });
+    edns.options.push(EdnsOption::ClientSubnet {
+        family: 1,
+        source_prefix: 24,
+        scope_prefix: 0,
+        address: vec![192, 0, 2],
+    });
+    edns.options.push(EdnsOption::UNKNOWN {
+        code: 65001,
+        data: vec![0xde, 0xad],
+    });
+
+    let mut packet = DnsPacket::new();
+    packet.questioins.push(DnsQuestion::new(
+        "example.com".parse().unwrap(),
+        QueryType::A,
+    ));
+    packet.resources.push(DnsRecord::A {
+        domain: "example.com".parse().unwrap(),
+        addr: Ipv4Addr::new(192, 0, 2, 1),
+        class: QueryClass::IN,
+        ttl: 60,
+    });
+    packet.edns = Some(edns);
+
+    let (parsed, _) = round_trip(&mut packet);
+
+    assert_eq!(parsed.header.resource_entries, 2);
+    assert_eq!(parsed.resources, packet.resources);
+    assert_eq!(parsed.edns, packet.edns);
+}
+
+#[test]
+fn vector_buffer_holds_more_than_512_bytes() {
+    let mut packet = DnsPacket::new();
+    packet.questioins.push(DnsQuestion::new(
+        "example.com".parse().unwrap(),
+        QueryType::A,
+    ));
+    for i in 0..100 {
+        packet.answers.push(DnsRecord::A {
+            domain: "example.com".parse().unwrap(),
+            addr: Ipv4Addr::new(192, 0, 2, i),
+            class: QueryClass::IN,
+            ttl: 60,
+        });
+    }
+
+    let mut small = BytePacketBuffer::new();
+    assert!(packet.write(&mut small).is_err());
+
+    let mut limited = VectorPacketBuffer::with_limit(1232);
+    assert!(packet.write(&mut limited).is_err());
+
+    let mut buffer = VectorPacketBuffer::new();
+    packet.write(&mut buffer).unwrap();
+    assert_eq!(buffer.pos(), 12 + 17 + 100 * 16);
+
+    let mut buffer = VectorPacketBuffer::from_bytes(&buffer.buf);
+    let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+    assert_eq!(parsed.answers, packet.answers);
+}
+
+#[test]
+fn soa_round_trip() {
+    let soa = DnsRecord::SOA {
+        domain: "example.com".parse().unwrap(),
+        mname: "ns1.example.com".parse().unwrap(),
+        rname: "hostmaster.example.com".parse().unwrap(),
+        serial: 2024010101,
+        refresh: 7200,
+        retry: 3600,
+        expire: 1209600,
+        minimum: 300,
+        class: QueryClass::IN,
+        ttl: 3600,
+    };
+
+    let mut packet = DnsPacket::new();
+    packet.header.rescode = ResultCode::NXDOMAIN;
+    packet.questioins.push(DnsQuestion::new(
+        "typo.example.com".parse().unwrap(),
+        QueryType::A,
+    ));
+    packet.authorities.push(soa.clone());
+
+    let (parsed, len) = round_trip(&mut packet);
+
+    // mname 和 rname 都压缩成一个 label 加指针
+    assert_eq!(len, 12 + 22 + (2 + 10 + 6 + 13 + 20));
+    assert_eq!(parsed.header.rescode, ResultCode::NXDOMAIN);
+    assert_eq!(parsed.get_soa(), Some(&soa));
+}
+
+#[test]
+fn soa_from_wire() {
+    #[rustfmt::skip]
+    let data = [
+        // header: id 0x1234, response, NXDOMAIN, 1 question, 1 authority
+        0x12, 0x34, 0x81, 0x83, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00,
+        // question: nope.org A IN
+        0x04, b'n', b'o', b'p', b'e', 0x03, b'o', b'r', b'g', 0x00, 0x00, 0x01, 0x00, 0x01,
+        // org SOA IN ttl 900
+        0xc0, 0x11, 0x00, 0x06, 0x00, 0x01, 0x00, 0x00, 0x03, 0x84, 0x00, 0x1f,
+        // mname a0.org
+        0x02, b'a', b'0', 0xc0, 0x11,
+        // rname noc.org
+        0x03, b'n', b'o', b'c', 0xc0, 0x11,
+        // serial, refresh, retry, expire, minimum
+        0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x07, 0x08, 0x00, 0x00, 0x03, 0x84,
+        0x00, 0x12, 0x75, 0x00, 0x00, 0x00, 0x0e, 0x10,
+    ];
+
+    let mut buffer = VectorPacketBuffer::from_bytes(&data);
+    let packet = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+    assert_eq!(
+        packet.get_soa(),
+        Some(&DnsRecord::SOA {
+            domain: "org".parse().unwrap(),
+            mname: "a0.org".parse().unwrap(),
+            rname: "noc.org".parse().unwrap(),
+            serial: 1,
+            refresh: 1800,
+            retry: 900,
+            expire: 1209600,
+            minimum: 3600,
+            class: QueryClass::IN,
+            ttl: 900,
+        })
+    );
+}
+
+#[test]
+fn txt_ptr_srv_round_trip() {
+    let mut packet = DnsPacket::new();
+    packet.questioins.push(DnsQuestion::new(
+        "_sip._tcp.example.com".parse().unwrap(),
+        QueryType::SRV,
+    ));
+    packet.answers.push(DnsRecord::SRV {
+        domain: "_sip._tcp.example.com".parse().unwrap(),
+        priority: 10,
+        weight: 60,
+        port: 5060,
+        host: "sip.example.com".parse().unwrap(),
+        class: QueryClass::IN,
+        ttl: 300,
+    });
+    packet.answers.push(DnsRecord::TXT {
+        domain: "example.com".parse().unwrap(),
+        data: vec![
+            b"v=spf1 include:_spf.example.com ~all".to_vec(),
+            vec![],
+            vec![b'x'; 255],
+        ],
+        class: QueryClass::IN,
+        ttl: 300,
+    });
+    packet.answers.push(DnsRecord::PTR {
+        domain: "1.2.0.192.in-addr.arpa".parse().unwrap(),
+        host: "sip.example.com".parse().unwrap(),
+        class: QueryClass::IN,
+        ttl: 300,
+    });
+
+    let mut buffer = VectorPacketBuffer::new();
+    packet.write(&mut buffer).unwrap();
+
+    // SRV 的 target 不压缩, 即使 example.com 前面已经写过了
+    let srv_target = b"\x03sip\x07example\x03com\x00";
+    assert!(buffer
+        .buf
+        .windows(srv_target.len())
+        .any(|w| w == srv_target));
+
+    let mut buffer = VectorPacketBuffer::from_bytes(&buffer.buf);
+    let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+    assert_eq!(parsed.answers, packet.answers);
+}
+
+#[test]
+fn txt_string_longer_than_255_bytes_is_rejected() {
+    let mut packet = DnsPacket::new();
+    packet.answers.push(DnsRecord::TXT {
+        domain: "example.com".parse().unwrap(),
+        data: vec![vec![b'x'; 256]],
+        class: QueryClass::IN,
+        ttl: 300,
+    });
+
+    assert!(packet.write(&mut VectorPacketBuffer::new()).is_err());
+}
+
+// TXT 里可以是任意字节, 不是 UTF-8 也要原样写回去
+#[test]
+fn binary_txt_strings_round_trip() {
+    let mut packet = DnsPacket::new();
+    packet.answers.push(DnsRecord::TXT {
+        domain: "example.com".parse().unwrap(),
+        data: vec![vec![0xff, 0xfe, b'a'], vec![0x80; 255]],
+        class: QueryClass::IN,
+        ttl: 300,
+    });
+
+    let mut buffer = VectorPacketBuffer::new();
+    packet.write(&mut buffer).unwrap();
+    let rdata = [3, 0xff, 0xfe, b'a'];
+    assert!(buffer.buf.windows(rdata.len()).any(|w| w == rdata));
+
+    let parsed = parse(&buffer.buf).unwrap();
+    assert_eq!(parsed.answers, packet.answers);
+    assert!(parsed.answers[0]
+        .to_string()
+        .starts_with("example.com. 300 IN TXT \"\\255\\254a\" \"\\128\\128"));
+}
+
+#[test]
+fn unknown_records_pass_through_unchanged() {
+    let record = DnsRecord::UNKNOWN {
+        domain: "example.com".parse().unwrap(),
+        qtype: 65280,
+        class: QueryClass::IN,
+        data: vec![0x0a, 0x00, 0x00, 0x01, 0xc0, 0x0c],
+        ttl: 300,
+    };
+
+    let mut packet = DnsPacket::new();
+    packet.answers.push(record.clone());
+    let (parsed, len) = round_trip(&mut packet);
+
+    assert_eq!(len, 12 + 13 + 10 + 6);
+    assert_eq!(parsed.answers, vec![record.clone()]);
+
+    let text = record.to_string();
+    assert_eq!(text, "example.com. 300 IN TYPE65280 \\# 6 0a000001c00c");
+    let rdata = text.split_once("TYPE65280 ").unwrap().1;
+    assert_eq!(
+        parse_generic_rdata(rdata).unwrap(),
+        vec![0x0a, 0x00, 0x00, 0x01, 0xc0, 0x0c]
+    );
+}
+
+#[test]
+fn generic_rdata_length_must_match() {
+    assert_eq!(parse_generic_rdata("\\# 0").unwrap(), Vec::<u8>::new());
+    assert_eq!(
+        parse_generic_rdata("\\# 4 0A00 0001").unwrap(),
+        vec![10, 0, 0, 1]
+    );
+    assert!(parse_generic_rdata("\\# 3 0a000001").is_err());
+    assert!(parse_generic_rdata("\\# 1 0").is_err());
+    assert!(parse_generic_rdata("0a000001").is_err());
+}
+
+#[test]
+fn chaos_class_is_preserved() {
+    let mut question = DnsQuestion::new("version.bind".parse().unwrap(), QueryType::TXT);
+    question.class = QueryClass::CH;
+
+    let mut packet = DnsPacket::new();
+    packet.questioins.push(question);
+    packet.answers.push(DnsRecord::TXT {
+        domain: "version.bind".parse().unwrap(),
+        data: vec![b"9.18.0".to_vec()],
+        class: QueryClass::CH,
+        ttl: 0,
+    });
+    packet.answers.push(DnsRecord::UNKNOWN {
+        domain: "example.com".parse().unwrap(),
+        qtype: 65280,
+        data: vec![],
+        class: QueryClass::UNKNOWN(1234),
+        ttl: 0,
+    });
+
+    let (parsed, _) = round_trip(&mut packet);
+
+    assert_eq!(parsed.questioins, packet.questioins);
+    assert_eq!(parsed.answers, packet.answers);
+    assert_eq!(
+        parsed.answers[0].to_string(),
+        "version.bind. 0 CH TXT \"9.18.0\""
+    );
+    assert_eq!(
+        parsed.answers[1].to_string(),
+        "example.com. 0 CLASS1234 TYPE65280 \\# 0"
+    );
+}
+
+fn parse(data: &[u8]) -> Result<DnsPacket, Error> {
+    DnsPacket::from_buffer(&mut VectorPacketBuffer::from_bytes(data))
+}
+
+// header: id 1, 1 question
+const ONE_QUESTION: [u8; 12] = [0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+
+#[test]
+fn malformed_packets_report_where_they_failed() {
+    let mut truncated = ONE_QUESTION.to_vec();
+    truncated.extend_from_slice(&[3, b'c', b'o', b'm', 0, 0]);
+    assert!(matches!(
+        parse(&truncated),
+        Err(Error::Truncated { offset: 18 })
+    ));
+
+    // 指针指向自己
+    let mut looped = ONE_QUESTION.to_vec();
+    looped.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1]);
+    assert!(matches!(
+        parse(&looped),
+        Err(Error::PointerLoop { offset: 12 })
+    ));
+
+    let mut reserved = ONE_QUESTION.to_vec();
+    reserved.extend_from_slice(&[0x41, b'a', 0, 0, 1, 0, 1]);
+    assert!(matches!(
+        parse(&reserved),
+        Err(Error::BadLabel { offset: 12, .. })
+    ));
+
+    // 5 个 63 字节的 label, 超过 255
+    let mut long = ONE_QUESTION.to_vec();
+    for _ in 0..5 {
+        long.push(63);
+        long.extend_from_slice(&[b'a'; 63]);
+    }
+    long.extend_from_slice(&[0, 0, 1, 0, 1]);
+    assert!(matches!(
+        parse(&long),
+        Err(Error::NameTooLong { offset: 204 })
+    ));
+
+    // ANSWER SECTION 里出现 OPT
+    let mut opt = vec![0, 1, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0];
+    opt.extend_from_slice(&[0, 0, 41, 4, 0xd0, 0, 0, 0, 0, 0, 0]);
+    assert!(matches!(
+        parse(&opt),
+        Err(Error::UnsupportedType {
+            offset: 12,
+            qtype: 41
+        })
+    ));
+
+    // x A, RDLENGTH 是 8, 但是 A 只用 4 字节, 多出来的会被当成下一条记录
+    let mut long_a = vec![0, 1, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0];
+    long_a.extend_from_slice(&[1, b'x', 0, 0, 1, 0, 1, 0, 0, 0, 0, 0, 8]);
+    long_a.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+    assert!(matches!(
+        parse(&long_a),
+        Err(Error::Malformed {
+            offset: 25,
+            reason: "rdata length mismatch"
+        })
+    ));
+
+    // RDLENGTH 是 0, 后面的 4 字节不算这条记录的
+    let mut short_a = vec![0, 1, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0];
+    short_a.extend_from_slice(&[1, b'x', 0, 0, 1, 0, 1, 0, 0, 0, 0, 0, 0]);
+    short_a.extend_from_slice(&[1, 2, 3, 4]);
+    assert!(matches!(
+        parse(&short_a),
+        Err(Error::Malformed {
+            offset: 25,
+            reason: "rdata length mismatch"
+        })
+    ));
+}
+
+#[test]
+fn every_glued_nameserver_is_a_candidate() {
+    let mut packet = DnsPacket::new();
+    for (host, addr) in [
+        ("a.gtld-servers.net", Ipv4Addr::new(192, 5, 6, 30)),
+        ("b.gtld-servers.net", Ipv4Addr::new(192, 33, 14, 30)),
+    ] {
+        packet.authorities.push(DnsRecord::NS {
+            domain: "com".parse().unwrap(),
+            host: host.parse().unwrap(),
+            class: QueryClass::IN,
+            ttl: 172800,
+        });
+        packet.resources.push(DnsRecord::A {
+            domain: host.parse().unwrap(),
+            addr,
+            class: QueryClass::IN,
+            ttl: 172800,
+        });
+    }
+    packet.authorities.push(DnsRecord::NS {
+        domain: "com".parse().unwrap(),
+        host: "c.gtld-servers.net".parse().unwrap(),
+        class: QueryClass::IN,
+        ttl: 172800,
+    });
+    let ipv6: Ipv6Addr = "2001:503:231d::2:30".parse().unwrap();
+    packet.resources.insert(
+        0,
+        DnsRecord::AAAA {
+            domain: "b.gtld-servers.net".parse().unwrap(),
+            addr: ipv6,
+            class: QueryClass::IN,
+            ttl: 172800,
+        },
+    );
+
+    assert_eq!(
+        packet.get_resolved_ns(&name("google.com")),
+        vec![
+            IpAddr::from(Ipv4Addr::new(192, 5, 6, 30)),
+            IpAddr::from(Ipv4Addr::new(192, 33, 14, 30)),
+            IpAddr::from(ipv6),
+        ]
+    );
+    assert_eq!(packet.get_unresolved_ns(&name("google.com")).len(), 3);
+}
+
+#[test]
+fn cname_is_not_read_as_ns() {
+    let mut packet = DnsPacket::new();
+    packet.answers.push(DnsRecord::CNAME {
+        domain: "www.example.com".parse().unwrap(),
+        host: "example.com".parse().unwrap(),
+        class: QueryClass::IN,
+        ttl: 300,
+    });
+
+    let (read, _) = round_trip(&mut packet);
+    match &read.answers[..] {
+        [DnsRecord::CNAME { host, .. }] => assert_eq!(host, "example.com"),
+        answers => panic!("unexpected answers {:?}", answers),
+    }
+}
+
+#[test]
+fn out_of_bailiwick_records_are_dropped() {
+    let ns = |domain: &str, host: &str| DnsRecord::NS {
+        domain: domain.parse().unwrap(),
+        host: host.parse().unwrap(),
+        class: QueryClass::IN,
+        ttl: 172800,
+    };
+    let a = |domain: &str, last: u8| DnsRecord::A {
+        domain: domain.parse().unwrap(),
+        addr: Ipv4Addr::new(192, 0, 2, last),
+        class: QueryClass::IN,
+        ttl: 172800,
+    };
+
+    // 问 com 的服务器 www.google.com, 它顺带塞了一条 example.org 的记录
+    let mut packet = DnsPacket::new();
+    packet.answers.push(a("www.example.org", 66));
+    packet.authorities.push(ns("google.com", "ns1.google.com"));
+    packet.authorities.push(ns("google.com", "ns.example.org"));
+    packet.resources.push(a("ns1.google.com", 1));
+    packet.resources.push(a("ns.example.org", 2));
+
+    packet.retain_in_bailiwick(&name("com"));
+    assert!(packet.answers.is_empty());
+    assert_eq!(
+        packet.get_delegation(&name("www.google.com")),
+        Some(&name("google.com"))
+    );
+    assert_eq!(
+        packet.get_resolved_ns(&name("www.google.com")),
+        [IpAddr::from(Ipv4Addr::new(192, 0, 2, 1))]
+    );
+    // 没有 glue 的 NS 还是可以用, 只是要自己去查它的地址
+    assert_eq!(packet.get_unresolved_ns(&name("www.google.com")).len(), 2);
+
+    // com 的 NS 不能用来回答 evilcom
+    let mut packet = DnsPacket::new();
+    packet.authorities.push(ns("com", "a.gtld-servers.net"));
+    packet.resources.push(a("a.gtld-servers.net", 30));
+    assert_eq!(packet.get_delegation(&name("evilcom")), None);
+    assert!(packet.get_resolved_ns(&name("evilcom")).is_empty());
+}
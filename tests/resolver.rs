@@ -0,0 +1,353 @@
+use dns_self::byte_packet_buffer::{
+    DnsPacket, DnsQuestion, DnsRecord, QueryClass, QueryType, ResultCode, VectorPacketBuffer,
+};
+use dns_self::error::Error;
+use dns_self::root_hints::RootHints;
+use dns_self::server_proxy::{Limits, Resolver};
+use std::net::{Ipv4Addr, Ipv6Addr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+type Answer = fn(&DnsQuestion) -> DnsPacket;
+type Seen = Arc<Mutex<Vec<DnsQuestion>>>;
+
+// 在 127.0.0.1, 127.0.0.2 ... 上各起一个假的权威服务器, 都用同一个端口
+// 返回端口和每个服务器收到的 question
+fn fake_servers(answers: &[Answer]) -> (u16, Vec<Seen>) {
+    let first = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+    let port = first.local_addr().unwrap().port();
+    let mut sockets = vec![first];
+    for i in 2..=answers.len() {
+        sockets.push(UdpSocket::bind((Ipv4Addr::new(127, 0, 0, i as u8), port)).unwrap());
+    }
+
+    let seen = sockets
+        .into_iter()
+        .zip(answers.iter().copied())
+        .map(|(socket, answer)| {
+            let seen = Seen::default();
+            let log = seen.clone();
+            thread::spawn(move || loop {
+                let mut data = [0; 512];
+                let (size, src) = socket.recv_from(&mut data).unwrap();
+                let request =
+                    DnsPacket::from_buffer(&mut VectorPacketBuffer::from_bytes(&data[..size]))
+                        .unwrap();
+                let question = request.questioins[0].clone();
+                log.lock().unwrap().push(question.clone());
+
+                let mut response = answer(&question);
+                response.header.id = request.header.id;
+                response.header.response = true;
+                response.questioins = vec![question];
+                let mut buffer = VectorPacketBuffer::new();
+                response.write(&mut buffer).unwrap();
+                socket.send_to(&buffer.buf, src).unwrap();
+            });
+            seen
+        })
+        .collect();
+
+    (port, seen)
+}
+
+// 根只有 127.0.0.1 这一台
+fn fake_resolver(port: u16) -> Resolver {
+    let hints = RootHints::parse(". NS root.test.\nroot.test. A 127.0.0.1\n").unwrap();
+    Resolver::with_root_hints(hints).with_upstream_port(port)
+}
+
+fn names(seen: &Seen) -> Vec<String> {
+    let seen = seen.lock().unwrap();
+    seen.iter()
+        .map(|q| format!("{} {:?}", q.name, q.qtype))
+        .collect()
+}
+
+fn ns(domain: &str, host: &str) -> DnsRecord {
+    DnsRecord::NS {
+        domain: domain.parse().unwrap(),
+        host: host.parse().unwrap(),
+        class: QueryClass::IN,
+        ttl: 300,
+    }
+}
+
+fn a(domain: &str, addr: [u8; 4]) -> DnsRecord {
+    DnsRecord::A {
+        domain: domain.parse().unwrap(),
+        addr: Ipv4Addr::from(addr),
+        class: QueryClass::IN,
+        ttl: 300,
+    }
+}
+
+fn packet(rescode: ResultCode, answers: Vec<DnsRecord>, authorities: Vec<DnsRecord>) -> DnsPacket {
+    let mut packet = DnsPacket::new();
+    packet.header.rescode = rescode;
+    packet.answers = answers;
+    packet.authorities = authorities;
+    packet
+}
+
+fn cname(domain: &str, host: &str) -> DnsRecord {
+    DnsRecord::CNAME {
+        domain: domain.parse().unwrap(),
+        host: host.parse().unwrap(),
+        class: QueryClass::IN,
+        ttl: 300,
+    }
+}
+
+// 只用缓存里的记录, 不会发出任何查询
+#[test]
+fn cname_chains_are_followed_to_the_answer() {
+    let resolver = Resolver::new();
+    resolver.cache().store(&[
+        cname("www.example.com", "cdn.example.net"),
+        cname("cdn.example.net", "edge.example.org"),
+        DnsRecord::A {
+            domain: "edge.example.org".parse().unwrap(),
+            addr: Ipv4Addr::new(192, 0, 2, 1),
+            class: QueryClass::IN,
+            ttl: 300,
+        },
+    ]);
+
+    let response = resolver
+        .recursive_lookup(&"www.example.com".parse().unwrap(), QueryType::A)
+        .unwrap();
+    assert_eq!(response.header.rescode, ResultCode::NOERROR);
+    assert_eq!(response.questioins[0].name, "www.example.com");
+    let types: Vec<QueryType> = response.answers.iter().map(|r| r.query_type()).collect();
+    assert_eq!(types, [QueryType::CNAME, QueryType::CNAME, QueryType::A]);
+    assert_eq!(response.answers[2].domain(), "edge.example.org");
+}
+
+#[test]
+fn cname_loops_stop() {
+    let resolver = Resolver::new();
+    resolver.cache().store(&[
+        cname("a.example.com", "b.example.com"),
+        cname("b.example.com", "a.example.com"),
+    ]);
+
+    let response = resolver
+        .recursive_lookup(&"a.example.com".parse().unwrap(), QueryType::A)
+        .unwrap();
+    assert_eq!(response.answers.len(), 2);
+}
+
+// 额度为 0 时不会发出任何查询, 直接失败
+#[test]
+fn exhausted_budget_fails_without_querying() {
+    let resolver = Resolver::new().with_limits(Limits {
+        max_upstream_queries: 0,
+        ..Limits::default()
+    });
+
+    match resolver.recursive_lookup(&"www.example.com".parse().unwrap(), QueryType::A) {
+        Err(Error::BudgetExceeded(_)) => {}
+        other => panic!("expected budget error, got {:?}", other),
+    }
+}
+
+// 委派没有 glue, 第一个 NS 的地址回 REFUSED 时要去查下一个 NS
+#[test]
+fn unreachable_ns_without_glue_fails_over_to_the_next() {
+    fn root(q: &DnsQuestion) -> DnsPacket {
+        match q.name.to_string().as_str() {
+            "ns1.dead.test" if q.qtype == QueryType::A => packet(
+                ResultCode::NOERROR,
+                vec![a("ns1.dead.test", [127, 0, 0, 2])],
+                vec![],
+            ),
+            "ns2.alive.test" if q.qtype == QueryType::A => packet(
+                ResultCode::NOERROR,
+                vec![a("ns2.alive.test", [127, 0, 0, 3])],
+                vec![],
+            ),
+            _ if q.name.is_subdomain_of(&"example.test".parse().unwrap()) => packet(
+                ResultCode::NOERROR,
+                vec![],
+                vec![
+                    ns("example.test", "ns1.dead.test"),
+                    ns("example.test", "ns2.alive.test"),
+                ],
+            ),
+            _ => packet(ResultCode::NOERROR, vec![], vec![]),
+        }
+    }
+    fn dead(_: &DnsQuestion) -> DnsPacket {
+        packet(ResultCode::REFUSED, vec![], vec![])
+    }
+    fn alive(q: &DnsQuestion) -> DnsPacket {
+        packet(
+            ResultCode::NOERROR,
+            vec![a(&q.name.to_string(), [192, 0, 2, 1])],
+            vec![],
+        )
+    }
+
+    let (port, seen) = fake_servers(&[root, dead, alive]);
+    let resolver = fake_resolver(port);
+
+    let response = resolver
+        .recursive_lookup(&"www.example.test".parse().unwrap(), QueryType::A)
+        .unwrap();
+    assert_eq!(response.header.rescode, ResultCode::NOERROR);
+    assert_eq!(response.get_addrs(), [Ipv4Addr::new(192, 0, 2, 1)]);
+    assert_eq!(names(&seen[1]), ["www.example.test A"]);
+    assert_eq!(names(&seen[2]), ["www.example.test A"]);
+}
+
+// com 给的 glue 和 NS 只用来找下一层, 客户端问的时候要去问 example.test 自己
+#[test]
+fn referral_glue_is_not_served_as_an_answer() {
+    fn root(_: &DnsQuestion) -> DnsPacket {
+        let mut referral = packet(
+            ResultCode::NOERROR,
+            vec![],
+            vec![ns("example.test", "ns1.example.test")],
+        );
+        referral.resources = vec![a("ns1.example.test", [127, 0, 0, 2])];
+        referral
+    }
+    fn child(q: &DnsQuestion) -> DnsPacket {
+        match q.qtype {
+            QueryType::NS => packet(
+                ResultCode::NOERROR,
+                vec![ns("example.test", "ns1.example.test")],
+                vec![],
+            ),
+            _ if q.name == "ns1.example.test" => packet(
+                ResultCode::NOERROR,
+                vec![a("ns1.example.test", [127, 0, 0, 2])],
+                vec![],
+            ),
+            _ => packet(
+                ResultCode::NOERROR,
+                vec![a(&q.name.to_string(), [192, 0, 2, 1])],
+                vec![],
+            ),
+        }
+    }
+
+    let (port, seen) = fake_servers(&[root, child]);
+    let resolver = fake_resolver(port);
+
+    resolver
+        .recursive_lookup(&"www.example.test".parse().unwrap(), QueryType::A)
+        .unwrap();
+    assert!(resolver
+        .cache()
+        .lookup(&"ns1.example.test".parse().unwrap(), QueryType::A)
+        .is_none());
+
+    let glue = resolver
+        .recursive_lookup(&"ns1.example.test".parse().unwrap(), QueryType::A)
+        .unwrap();
+    assert_eq!(glue.get_addrs(), [Ipv4Addr::new(127, 0, 0, 2)]);
+    let delegation = resolver
+        .recursive_lookup(&"example.test".parse().unwrap(), QueryType::NS)
+        .unwrap();
+    assert_eq!(delegation.answers.len(), 1);
+
+    // 委派还是缓存着的, 后面的查询直接去问 example.test 的服务器
+    assert_eq!(names(&seen[0]), ["www.example.test A"]);
+    assert_eq!(
+        names(&seen[1]),
+        [
+            "www.example.test A",
+            "ns1.example.test A",
+            "example.test NS"
+        ]
+    );
+}
+
+// a.test 的 NS 在 b.test 下面, b.test 的 NS 又在 a.test 下面, 永远查不到地址
+#[test]
+fn circular_delegations_stop_within_the_budget() {
+    fn root(q: &DnsQuestion) -> DnsPacket {
+        if q.name.is_subdomain_of(&"a.test".parse().unwrap()) {
+            packet(ResultCode::NOERROR, vec![], vec![ns("a.test", "ns.b.test")])
+        } else {
+            packet(ResultCode::NOERROR, vec![], vec![ns("b.test", "ns.a.test")])
+        }
+    }
+
+    let (port, seen) = fake_servers(&[root]);
+
+    let resolver = fake_resolver(port);
+    match resolver.recursive_lookup(&"www.a.test".parse().unwrap(), QueryType::A) {
+        Err(Error::BudgetExceeded(what)) => assert_eq!(what, "nested nameserver lookups"),
+        other => panic!("expected budget error, got {:?}", other),
+    }
+    // 每一层只问根一次, 最多嵌套 4 层
+    assert_eq!(seen[0].lock().unwrap().len(), 5);
+
+    // 嵌套的层数够多时, 先用完的是委派的次数
+    let resolver = fake_resolver(port).with_limits(Limits {
+        max_referrals: 3,
+        max_ns_depth: 100,
+        ..Limits::default()
+    });
+    match resolver.recursive_lookup(&"www.a.test".parse().unwrap(), QueryType::A) {
+        Err(Error::BudgetExceeded(what)) => assert_eq!(what, "referrals"),
+        other => panic!("expected budget error, got {:?}", other),
+    }
+    assert_eq!(seen[0].lock().unwrap().len(), 5 + 4);
+}
+
+// 根只看到 com, com 对空的中间节点 example.com 回 NXDOMAIN 时改问完整的名字
+#[test]
+fn minimised_queries_fall_back_to_the_full_name() {
+    fn root(_: &DnsQuestion) -> DnsPacket {
+        let mut referral = packet(ResultCode::NOERROR, vec![], vec![ns("com", "a.gtld.com")]);
+        referral.resources = vec![a("a.gtld.com", [127, 0, 0, 2])];
+        referral
+    }
+    fn tld(q: &DnsQuestion) -> DnsPacket {
+        if q.name != "www.example.com" {
+            return packet(ResultCode::NXDOMAIN, vec![], vec![]);
+        }
+        let aaaa = DnsRecord::AAAA {
+            domain: "www.example.com".parse().unwrap(),
+            addr: Ipv6Addr::LOCALHOST,
+            class: QueryClass::IN,
+            ttl: 300,
+        };
+        packet(ResultCode::NOERROR, vec![aaaa], vec![])
+    }
+
+    let (port, seen) = fake_servers(&[root, tld]);
+    let resolver = fake_resolver(port).with_qname_minimisation(true);
+
+    let response = resolver
+        .recursive_lookup(&"www.example.com".parse().unwrap(), QueryType::AAAA)
+        .unwrap();
+    assert_eq!(response.header.rescode, ResultCode::NOERROR);
+    assert_eq!(response.get_addrs(), [Ipv6Addr::LOCALHOST]);
+
+    assert_eq!(names(&seen[0]), ["com A"]);
+    assert_eq!(names(&seen[1]), ["example.com A", "www.example.com AAAA"]);
+}
+
+// 根都不能用时 priming 很快就放弃, 不会把所有根都试好几轮
+#[test]
+fn priming_gives_up_quickly() {
+    fn servfail(_: &DnsQuestion) -> DnsPacket {
+        packet(ResultCode::SERVFAIL, vec![], vec![])
+    }
+
+    let (port, seen) = fake_servers(&[servfail]);
+    let hints: String = (1..=13)
+        .map(|i| format!(". NS r{0}.test.\nr{0}.test. A 127.0.0.1\n", i))
+        .collect();
+    let resolver =
+        Resolver::with_root_hints(RootHints::parse(&hints).unwrap()).with_upstream_port(port);
+
+    assert!(resolver.prime().is_err());
+    assert_eq!(seen[0].lock().unwrap().len(), 4);
+    assert_eq!(resolver.root_hints().servers().len(), 13);
+}
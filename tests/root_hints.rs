@@ -0,0 +1,66 @@
+use dns_self::byte_packet_buffer::{DnsPacket, DnsRecord, QueryClass};
+use dns_self::dns_name::DnsName;
+use dns_self::error::Error;
+use dns_self::root_hints::RootHints;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+#[test]
+fn iana_hints_have_all_thirteen_roots() {
+    let hints = RootHints::iana();
+    assert_eq!(hints.servers().len(), 13);
+    assert_eq!(hints.ipv4_addrs().len(), 13);
+    assert_eq!(hints.ipv6_addrs().len(), 13);
+
+    let a = &hints.servers()[0];
+    assert_eq!(a.host, "a.root-servers.net");
+    assert_eq!(a.ipv4, [Ipv4Addr::new(198, 41, 0, 4)]);
+    assert_eq!(a.ipv6, ["2001:503:ba3e::2:30".parse::<Ipv6Addr>().unwrap()]);
+}
+
+#[test]
+fn lab_hints_file() {
+    let hints = RootHints::parse(
+        "; 实验室的根\n\
+         .              NS    ns.lab.\n\
+         ns.lab.  3600  IN A  10.0.0.53 ; 注释\n\
+         \n\
+         unused.lab.    A     10.0.0.54\n",
+    )
+    .unwrap();
+
+    assert_eq!(hints.servers().len(), 1);
+    assert_eq!(hints.ipv4_addrs(), [Ipv4Addr::new(10, 0, 0, 53)]);
+    assert!(hints.ipv6_addrs().is_empty());
+}
+
+#[test]
+fn bad_hints_are_rejected() {
+    match RootHints::parse(".  NS  ns.lab.\nns.lab.  A  10.0.0.300\n") {
+        Err(Error::Syntax(reason)) => assert!(reason.starts_with("line 2:"), "{}", reason),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(RootHints::parse(".  SOA  ns.lab.\n").is_err());
+    // 没有地址的 NS 不能用
+    assert!(RootHints::parse(".  NS  ns.lab.\n").is_err());
+}
+
+#[test]
+fn priming_response_replaces_hints() {
+    let mut response = DnsPacket::new();
+    response.answers.push(DnsRecord::NS {
+        domain: DnsName::root(),
+        host: "a.root-servers.net".parse().unwrap(),
+        class: QueryClass::IN,
+        ttl: 518400,
+    });
+    assert!(RootHints::from_priming_response(&response).is_none());
+
+    response.resources.push(DnsRecord::A {
+        domain: "a.root-servers.net".parse().unwrap(),
+        addr: Ipv4Addr::new(198, 41, 0, 4),
+        class: QueryClass::IN,
+        ttl: 518400,
+    });
+    let hints = RootHints::from_priming_response(&response).unwrap();
+    assert_eq!(hints.ipv4_addrs(), [Ipv4Addr::new(198, 41, 0, 4)]);
+}
@@ -0,0 +1,200 @@
+use dns_self::byte_packet_buffer::{
+    DnsPacket, DnsQuestion, DnsRecord, QueryClass, QueryType, ResultCode, VectorPacketBuffer,
+};
+use dns_self::server_proxy::{self, Resolver};
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+fn serve_one_connection() -> (SocketAddr, JoinHandle<()>) {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        server_proxy::handle_tcp_connection(stream, &Resolver::new()).unwrap();
+    });
+
+    (addr, server)
+}
+
+fn send(stream: &mut TcpStream, packet: &mut DnsPacket) -> DnsPacket {
+    let mut buffer = VectorPacketBuffer::new();
+    packet.write(&mut buffer).unwrap();
+
+    send_raw(stream, &buffer.buf)
+}
+
+fn send_raw(stream: &mut TcpStream, data: &[u8]) -> DnsPacket {
+    stream
+        .write_all(&(data.len() as u16).to_be_bytes())
+        .unwrap();
+    stream.write_all(data).unwrap();
+
+    let mut len = [0; 2];
+    stream.read_exact(&mut len).unwrap();
+    let mut data = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut data).unwrap();
+
+    DnsPacket::from_buffer(&mut VectorPacketBuffer::from_bytes(&data)).unwrap()
+}
+
+fn chaos_query(name: &str) -> DnsPacket {
+    let mut question = DnsQuestion::new(name.parse().unwrap(), QueryType::TXT);
+    question.class = QueryClass::CH;
+
+    let mut packet = DnsPacket::new();
+    packet.questioins.push(question);
+    packet
+}
+
+#[test]
+fn several_queries_on_one_connection() {
+    let (addr, server) = serve_one_connection();
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    for id in [1, 2, 3] {
+        // 没有 question 的请求不需要出去查, 直接回 FORMERR
+        let mut packet = DnsPacket::new();
+        packet.header.id = id;
+        let response = send(&mut stream, &mut packet);
+        assert_eq!(response.header.id, id);
+        assert!(response.header.response);
+        assert_eq!(response.header.rescode, ResultCode::FORMERR);
+    }
+
+    drop(stream);
+    server.join().unwrap();
+}
+
+#[test]
+fn chaos_queries_are_not_resolved_as_in() {
+    let (addr, server) = serve_one_connection();
+    let mut stream = TcpStream::connect(addr).unwrap();
+
+    let response = send(&mut stream, &mut chaos_query("version.bind"));
+    assert_eq!(response.header.rescode, ResultCode::NOERROR);
+    assert_eq!(response.questioins[0].class, QueryClass::CH);
+    match &response.answers[..] {
+        [DnsRecord::TXT { class, data, .. }] => {
+            assert_eq!(*class, QueryClass::CH);
+            assert!(data[0].starts_with(b"dns_self "));
+        }
+        answers => panic!("unexpected answers {:?}", answers),
+    }
+
+    let response = send(&mut stream, &mut chaos_query("hostname.bind"));
+    assert_eq!(response.header.rescode, ResultCode::REFUSED);
+    assert!(response.answers.is_empty());
+
+    drop(stream);
+    server.join().unwrap();
+}
+
+#[test]
+fn bad_requests_get_an_error_reply() {
+    let (addr, server) = serve_one_connection();
+    let mut stream = TcpStream::connect(addr).unwrap();
+
+    // header 说有一个 question, 但后面什么都没有
+    let response = send_raw(
+        &mut stream,
+        &[0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0],
+    );
+    assert_eq!(response.header.id, 0x1234);
+    assert!(response.header.response);
+    assert!(response.header.recursion_desired);
+    assert_eq!(response.header.rescode, ResultCode::FORMERR);
+
+    // opcode 2 是 STATUS
+    let mut packet = DnsPacket::new();
+    packet.header.id = 7;
+    packet.header.opcode = 2;
+    let response = send(&mut stream, &mut packet);
+    assert_eq!(response.header.id, 7);
+    assert_eq!(response.header.opcode, 2);
+    assert_eq!(response.header.rescode, ResultCode::NOTIMP);
+
+    let mut packet = DnsPacket::new();
+    packet.questioins.push(DnsQuestion::new(
+        "example.com".parse().unwrap(),
+        QueryType::UNKNOWN(252),
+    ));
+    let response = send(&mut stream, &mut packet);
+    assert_eq!(response.header.rescode, ResultCode::REFUSED);
+    assert_eq!(response.questioins[0].qtype, QueryType::UNKNOWN(252));
+
+    drop(stream);
+    server.join().unwrap();
+}
+
+fn read_query(data: &[u8]) -> DnsPacket {
+    let mut query = DnsPacket::from_buffer(&mut VectorPacketBuffer::from_bytes(data)).unwrap();
+    query.header.response = true;
+    query
+}
+
+fn encode(packet: &mut DnsPacket) -> Vec<u8> {
+    let mut buffer = VectorPacketBuffer::new();
+    packet.write(&mut buffer).unwrap();
+    buffer.buf
+}
+
+#[test]
+fn truncated_udp_answers_are_retried_over_tcp() {
+    // udp 和 tcp 要在同一个端口上
+    let (udp, tcp) = loop {
+        let udp = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        if let Ok(tcp) = TcpListener::bind(udp.local_addr().unwrap()) {
+            break (udp, tcp);
+        }
+    };
+    let port = udp.local_addr().unwrap().port();
+
+    let udp_server = thread::spawn(move || {
+        let mut data = [0; 512];
+        let (len, src) = udp.recv_from(&mut data).unwrap();
+        let mut response = read_query(&data[..len]);
+        response.header.truncated_message = true;
+        udp.send_to(&encode(&mut response), src).unwrap();
+    });
+    let tcp_server = thread::spawn(move || {
+        let (mut stream, _) = tcp.accept().unwrap();
+        let mut len = [0; 2];
+        stream.read_exact(&mut len).unwrap();
+        let mut data = vec![0; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut data).unwrap();
+
+        let mut response = read_query(&data);
+        response.answers.push(DnsRecord::A {
+            domain: response.questioins[0].name.clone(),
+            addr: Ipv4Addr::new(192, 0, 2, 1),
+            class: QueryClass::IN,
+            ttl: 300,
+        });
+        let data = encode(&mut response);
+        stream
+            .write_all(&(data.len() as u16).to_be_bytes())
+            .unwrap();
+        stream.write_all(&data).unwrap();
+    });
+
+    let response = server_proxy::lookup(
+        &"www.example.com".parse().unwrap(),
+        QueryType::A,
+        (IpAddr::V4(Ipv4Addr::LOCALHOST), port),
+        Duration::from_secs(2),
+    )
+    .unwrap();
+    assert!(!response.header.truncated_message);
+    match &response.answers[..] {
+        [DnsRecord::A { domain, addr, .. }] => {
+            assert_eq!(*domain, "www.example.com");
+            assert_eq!(*addr, Ipv4Addr::new(192, 0, 2, 1));
+        }
+        answers => panic!("unexpected answers {:?}", answers),
+    }
+
+    udp_server.join().unwrap();
+    tcp_server.join().unwrap();
+}
@@ -0,0 +1,92 @@
+use dns_self::byte_packet_buffer::{
+    DnsPacket, DnsQuestion, DnsRecord, QueryClass, QueryType, ResultCode, VectorPacketBuffer,
+};
+use dns_self::server_proxy::{self, Resolver};
+use std::net::UdpSocket;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn resolver_can_be_shared_between_threads() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Resolver>();
+}
+
+#[test]
+fn worker_pool_answers_every_client() {
+    let socket = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+    let addr = socket.local_addr().unwrap();
+    thread::spawn(move || server_proxy::serve_udp(socket, Arc::new(Resolver::new()), 2, 16));
+
+    let clients: Vec<_> = (0..4u16)
+        .map(|id| {
+            thread::spawn(move || {
+                let mut question =
+                    DnsQuestion::new("version.bind".parse().unwrap(), QueryType::TXT);
+                question.class = QueryClass::CH;
+                let mut packet = DnsPacket::new();
+                packet.header.id = id;
+                packet.questioins.push(question);
+
+                let mut buffer = VectorPacketBuffer::new();
+                packet.write(&mut buffer).unwrap();
+
+                let client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+                client
+                    .set_read_timeout(Some(Duration::from_secs(5)))
+                    .unwrap();
+                client.send_to(&buffer.buf, addr).unwrap();
+
+                let mut data = [0; 512];
+                let (size, _) = client.recv_from(&mut data).unwrap();
+                DnsPacket::from_buffer(&mut VectorPacketBuffer::from_bytes(&data[..size])).unwrap()
+            })
+        })
+        .collect();
+
+    for (id, client) in clients.into_iter().enumerate() {
+        let response = client.join().unwrap();
+        assert_eq!(response.header.id, id as u16);
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert_eq!(response.answers.len(), 1);
+    }
+}
+
+// 缓存里的记录写不进报文时回 SERVFAIL, 不能把写了一半的 buffer 发出去
+#[test]
+fn unencodable_answers_become_servfail() {
+    let resolver = Resolver::new();
+    resolver.cache().store(&[DnsRecord::TXT {
+        domain: "big.example.com".parse().unwrap(),
+        data: vec![vec![b'x'; 256]],
+        class: QueryClass::IN,
+        ttl: 300,
+    }]);
+    let socket = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+    let addr = socket.local_addr().unwrap();
+    thread::spawn(move || server_proxy::serve_udp(socket, Arc::new(resolver), 1, 4));
+
+    let mut packet = DnsPacket::new();
+    packet.header.id = 7;
+    packet.questioins.push(DnsQuestion::new(
+        "big.example.com".parse().unwrap(),
+        QueryType::TXT,
+    ));
+    let mut buffer = VectorPacketBuffer::new();
+    packet.write(&mut buffer).unwrap();
+
+    let client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    client.send_to(&buffer.buf, addr).unwrap();
+    let mut data = [0; 512];
+    let (size, _) = client.recv_from(&mut data).unwrap();
+
+    let response =
+        DnsPacket::from_buffer(&mut VectorPacketBuffer::from_bytes(&data[..size])).unwrap();
+    assert_eq!(response.header.id, 7);
+    assert_eq!(response.header.rescode, ResultCode::SERVFAIL);
+    assert!(response.answers.is_empty());
+}
@@ -0,0 +1,168 @@
+use dns_self::byte_packet_buffer::{
+    BytePacketBuffer, DnsPacket, DnsRecord, QueryClass, QueryType, VectorPacketBuffer,
+};
+use dns_self::server_proxy;
+use std::net::{Ipv4Addr, Ipv6Addr, UdpSocket};
+use std::thread;
+use std::time::Duration;
+
+fn reply(request: &DnsPacket, id: u16, addr: Ipv4Addr) -> Vec<u8> {
+    let mut packet = DnsPacket::new();
+    packet.header.id = id;
+    packet.header.response = true;
+    packet.questioins = request.questioins.clone();
+    packet.answers.push(DnsRecord::A {
+        domain: request.questioins[0].name.clone(),
+        addr,
+        class: QueryClass::IN,
+        ttl: 60,
+    });
+
+    let mut buffer = BytePacketBuffer::new();
+    packet.write(&mut buffer).unwrap();
+    buffer.buf[..buffer.pos].to_vec()
+}
+
+// 回复一个请求, 返回请求的 id
+fn answer_one(upstream: &UdpSocket, addr: Ipv4Addr) -> u16 {
+    let mut data = [0; 512];
+    let (size, client) = upstream.recv_from(&mut data).unwrap();
+    let request =
+        DnsPacket::from_buffer(&mut VectorPacketBuffer::from_bytes(&data[..size])).unwrap();
+    upstream
+        .send_to(&reply(&request, request.header.id, addr), client)
+        .unwrap();
+
+    request.header.id
+}
+
+#[test]
+fn case_randomized_responses_must_echo_the_case() {
+    let upstream = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+    let port = upstream.local_addr().unwrap().port();
+
+    let server = thread::spawn(move || {
+        let mut data = [0; 512];
+        let (size, client) = upstream.recv_from(&mut data).unwrap();
+        let request =
+            DnsPacket::from_buffer(&mut VectorPacketBuffer::from_bytes(&data[..size])).unwrap();
+        let id = request.header.id;
+        let name = request.questioins[0].name.clone();
+
+        // id 对但 question 被改成了小写, 像是不会保留大小写的伪造者
+        let mut lowered = request.clone();
+        lowered.questioins[0].name = name.to_lowercase();
+        if lowered.questioins[0].name.to_string() != name.to_string() {
+            let spoofed = reply(&lowered, id, Ipv4Addr::new(6, 6, 6, 6));
+            upstream.send_to(&spoofed, client).unwrap();
+        }
+
+        let good = reply(&request, id, Ipv4Addr::new(1, 2, 3, 4));
+        upstream.send_to(&good, client).unwrap();
+
+        name
+    });
+
+    let response = server_proxy::lookup_with(
+        &"www.example.com".parse().unwrap(),
+        QueryType::A,
+        (Ipv4Addr::LOCALHOST.into(), port),
+        Duration::from_secs(5),
+        true,
+    )
+    .unwrap();
+    let name = server.join().unwrap();
+
+    // DnsName 的 == 不区分大小写, 用文本形式逐字比较
+    assert_eq!(name, "www.example.com");
+    assert_eq!(response.questioins[0].name.to_string(), name.to_string());
+    assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(1, 2, 3, 4)));
+}
+
+#[test]
+fn only_matching_responses_are_accepted() {
+    let upstream = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+    let port = upstream.local_addr().unwrap().port();
+
+    let server = thread::spawn(move || {
+        let mut data = [0; 512];
+        let (size, client) = upstream.recv_from(&mut data).unwrap();
+        let request =
+            DnsPacket::from_buffer(&mut VectorPacketBuffer::from_bytes(&data[..size])).unwrap();
+        let id = request.header.id;
+
+        // 别的地址发来的, id 对但来源不对
+        let spoofer = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let spoofed = reply(&request, id, Ipv4Addr::new(6, 6, 6, 6));
+        spoofer.send_to(&spoofed, client).unwrap();
+
+        // 来源对但 id 不对
+        let wrong_id = reply(&request, id.wrapping_add(1), Ipv4Addr::new(6, 6, 6, 6));
+        upstream.send_to(&wrong_id, client).unwrap();
+
+        let good = reply(&request, id, Ipv4Addr::new(1, 2, 3, 4));
+        upstream.send_to(&good, client).unwrap();
+
+        id
+    });
+
+    let response = server_proxy::lookup(
+        &"example.com".parse().unwrap(),
+        QueryType::A,
+        (Ipv4Addr::LOCALHOST.into(), port),
+        Duration::from_secs(5),
+    )
+    .unwrap();
+    let id = server.join().unwrap();
+
+    assert_eq!(response.header.id, id);
+    match &response.answers[..] {
+        [DnsRecord::A { addr, .. }] => assert_eq!(*addr, Ipv4Addr::new(1, 2, 3, 4)),
+        answers => panic!("unexpected answers {:?}", answers),
+    }
+}
+
+#[test]
+fn silent_servers_are_skipped() {
+    // 只绑定不回复
+    let silent = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+    let silent_port = silent.local_addr().unwrap().port();
+
+    let upstream = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+    let port = upstream.local_addr().unwrap().port();
+    let server = thread::spawn(move || answer_one(&upstream, Ipv4Addr::new(1, 2, 3, 4)));
+
+    let response = server_proxy::lookup_servers(
+        &"example.com".parse().unwrap(),
+        QueryType::A,
+        &[
+            (Ipv4Addr::LOCALHOST.into(), silent_port),
+            (Ipv4Addr::LOCALHOST.into(), port),
+        ],
+    )
+    .unwrap();
+
+    assert_eq!(response.header.id, server.join().unwrap());
+    assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(1, 2, 3, 4)));
+}
+
+#[test]
+fn ipv6_upstream() {
+    // 没有 IPv6 的环境就跳过
+    let upstream = match UdpSocket::bind(("::1", 0)) {
+        Ok(upstream) => upstream,
+        Err(_) => return,
+    };
+    let port = upstream.local_addr().unwrap().port();
+    let server = thread::spawn(move || answer_one(&upstream, Ipv4Addr::new(1, 2, 3, 4)));
+
+    let response = server_proxy::lookup(
+        &"example.com".parse().unwrap(),
+        QueryType::A,
+        (Ipv6Addr::LOCALHOST.into(), port),
+        Duration::from_secs(5),
+    )
+    .unwrap();
+
+    assert_eq!(response.header.id, server.join().unwrap());
+}